@@ -7,7 +7,7 @@ fn nasm(_in_asm: &str, out_obj: &str) {
     }
 
     let status = Command::new("touch")
-        .args(&[out_obj])
+        .args([out_obj])
         .status()
         .expect("Failed to run test");
 
@@ -22,5 +22,7 @@ fn nasm(_in_asm: &str, out_obj: &str) {
 }
 
 fn main() {
+    // The target directory is somewhere else when building as a dependency of the host tool
+    std::fs::create_dir_all("target").expect("Failed to create target directory");
     nasm("src/asm_routines.asm", "target/asm_routines.obj");
 }
@@ -2,7 +2,7 @@ ENTRY(entry);
 
 MEMORY
 {
-  FLAT : ORIGIN = 0x10000, LENGTH = 1024K
+  FLAT : ORIGIN = 0x10000, LENGTH = 192K
 }
 
 
@@ -0,0 +1,176 @@
+//! DMAR - DMA Remapping table (Intel VT-d)
+//!     - Describes the IOMMUs (DRHD units) of the system and which devices sit behind each one
+//!     - Reserved memory regions (RMRR) are used by devices for DMA behind the os' back, they must
+//!       never be handed out
+
+use crate::physmem::PhysMem;
+use super::{Result, Error, tables::TableInfo};
+
+use alloc::vec::Vec;
+
+/// Remapping structure types
+const DRHD: u16 = 0;
+const RMRR: u16 = 1;
+
+/// The DRHD unit covers all devices of its segment that are not explicitly listed elsewhere
+const INCLUDE_PCI_ALL: u8 = 1 << 0;
+
+/// A device, bridge or other entity a remapping structure applies to
+#[derive(Debug, Clone)]
+pub struct DeviceScope {
+    /// 1 = pci endpoint, 2 = pci bridge, 3 = I/O APIC, 4 = HPET, 5 = acpi namespace device
+    pub typ:            u8,
+
+    /// I/O APIC id, HPET number or acpi device number, depending on `typ`
+    pub enumeration_id: u8,
+
+    /// Bus number the path starts at
+    pub start_bus:      u8,
+
+    /// `(device, function)` pairs walking through bridges down to the device
+    pub path:           Vec<(u8, u8)>,
+}
+
+/// A DMA remapping hardware unit
+#[derive(Debug, Clone)]
+pub struct Drhd {
+    /// Physical address of the unit's registers
+    pub base:        u64,
+    pub segment:     u16,
+
+    /// Covers every device of `segment` that is not listed under another unit
+    pub include_all: bool,
+    pub scopes:      Vec<DeviceScope>,
+}
+
+/// A reserved memory region that `scopes` keep using for DMA
+#[derive(Debug, Clone)]
+pub struct Rmrr {
+    /// Physical range `[start, end)`
+    pub start:   u64,
+    pub end:     u64,
+    pub segment: u16,
+    pub scopes:  Vec<DeviceScope>,
+}
+
+/// Everything we parsed out of the DMAR
+#[derive(Debug, Default, Clone)]
+pub struct Dmar {
+    /// Maximum physical address width supported for DMA, in bits
+    pub host_address_width: u8,
+    pub flags:              u8,
+    pub units:              Vec<Drhd>,
+    pub reserved:           Vec<Rmrr>,
+}
+
+impl Dmar {
+    /// Parse out the DMAR described by `table`
+    pub fn parse<M: PhysMem>(mem: &M, table: &TableInfo) -> Result<Self> {
+        let mut dmar = Self {
+            host_address_width: mem.read::<u8>(table.payload())? + 1,
+            flags:              mem.read(table.payload() + 1)?,
+            ..Default::default()
+        };
+
+        for_each_structure(mem, table, |typ, ptr, end| {
+            match typ {
+                DRHD => {
+                    if end - ptr < 16 {
+                        return Err(Error::InvalidDMARStructure);
+                    }
+                    let flags: u8 = mem.read(ptr + 4)?;
+                    dmar.units.push(Drhd {
+                        base:        mem.read(ptr + 8)?,
+                        segment:     mem.read(ptr + 6)?,
+                        include_all: (flags & INCLUDE_PCI_ALL) != 0,
+                        scopes:      parse_scopes(mem, ptr + 16, end)?,
+                    });
+                }
+                RMRR => {
+                    let (start, rmrr_end) = rmrr_range(mem, ptr, end)?;
+                    dmar.reserved.push(Rmrr {
+                        start,
+                        end:     rmrr_end,
+                        segment: mem.read(ptr + 6)?,
+                        scopes:  parse_scopes(mem, ptr + 24, end)?,
+                    });
+                }
+                _ => {},
+            }
+            Ok(())
+        })?;
+        Ok(dmar)
+    }
+}
+
+/// Call `f` with the range `[start, end)` of every RMRR in the DMAR described by `table`. Nothing
+/// is allocated, so this can run before the heap is usable
+pub fn for_each_reserved<M: PhysMem>(mem: &M, table: &TableInfo, mut f: impl FnMut(u64, u64))
+        -> Result<()> {
+    for_each_structure(mem, table, |typ, ptr, end| {
+        if typ == RMRR {
+            let (start, end) = rmrr_range(mem, ptr, end)?;
+            f(start, end);
+        }
+        Ok(())
+    })
+}
+
+/// Call `f` with the type and range `[ptr, end)` of every remapping structure in the DMAR
+/// described by `table`
+fn for_each_structure<M: PhysMem>(mem: &M, table: &TableInfo,
+                                  mut f: impl FnMut(u16, u64, u64) -> Result<()>) -> Result<()> {
+    // Remapping structures follow 10 reserved bytes
+    let mut ptr = table.payload() + 12;
+    while ptr + 4 <= table.end() {
+        let typ: u16 = mem.read(ptr)?;
+        let len: u16 = mem.read(ptr + 2)?;
+        if len < 4 || ptr + len as u64 > table.end() {
+            return Err(Error::InvalidDMARStructure);
+        }
+        let end = ptr + len as u64;
+        f(typ, ptr, end)?;
+        ptr = end;
+    }
+    Ok(())
+}
+
+/// Physical range `[start, end)` of the RMRR structure in `[ptr, end)`
+fn rmrr_range<M: PhysMem>(mem: &M, ptr: u64, end: u64) -> Result<(u64, u64)> {
+    if end - ptr < 24 {
+        return Err(Error::InvalidDMARStructure);
+    }
+
+    // The limit is inclusive
+    let start: u64 = mem.read(ptr + 8)?;
+    let limit: u64 = mem.read(ptr + 16)?;
+    if limit < start {
+        return Err(Error::InvalidDMARStructure);
+    }
+    Ok((start, limit.saturating_add(1)))
+}
+
+/// Parse the device scopes in `[ptr, end)`
+fn parse_scopes<M: PhysMem>(mem: &M, mut ptr: u64, end: u64) -> Result<Vec<DeviceScope>> {
+    let mut scopes = Vec::new();
+    while ptr + 6 <= end {
+        let len: u8 = mem.read(ptr + 1)?;
+        if len < 6 || ptr + len as u64 > end {
+            return Err(Error::InvalidDMARStructure);
+        }
+
+        let mut path = Vec::new();
+        for entry in (ptr + 6..ptr + len as u64 - 1).step_by(2) {
+            path.push((mem.read(entry)?, mem.read(entry + 1)?));
+        }
+
+        scopes.push(DeviceScope {
+            typ:            mem.read(ptr)?,
+            enumeration_id: mem.read(ptr + 4)?,
+            start_bus:      mem.read(ptr + 5)?,
+            path,
+        });
+        ptr += len as u64;
+    }
+    Ok(scopes)
+}
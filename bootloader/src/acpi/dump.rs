@@ -0,0 +1,61 @@
+//! Dump of all acpi tables, so the tables of a machine can be inspected offline
+//!     - Stage-2 streams the dump over serial. Every table is framed by lines starting with
+//!       `@ACPI`, which lets the host tool pick them out of a log that also contains other output
+//!     - The rsdp and the rsdt/xsdt are dumped along with the tables, the rsdp under the signature
+//!       `RSDP`
+//!     - Format, all numbers in hex:
+//!         @ACPI BEGIN
+//!         @ACPI TABLE <signature bytes> <address> <length>
+//!         @ACPI DATA <up to 32 bytes>
+//!         @ACPI END <fnv-1a hash of the table>
+//!         @ACPI DONE <number of tables>
+//!     - `vfuzz acpi-dump <log> <dir>` turns this back into one `<signature>_<address>.dat` file
+//!       per table, which can be loaded back into memory at the same addresses
+
+use crate::physmem::PhysMem;
+use super::{Result, ParsedACPI};
+
+use core::fmt::Write;
+
+/// Number of table bytes per data line
+const BYTES_PER_LINE: usize = 32;
+
+/// Write the rsdp, the rsdt/xsdt and every table `acpi` found to `out`
+pub fn dump<M: PhysMem, W: Write>(mem: &M, acpi: &ParsedACPI, out: &mut W) -> Result<()> {
+    writeln!(out, "@ACPI BEGIN")?;
+    let mut num_tables = 0;
+    for (addr, length) in acpi.ranges() {
+        let signature = match &mem.read::<[u8; 8]>(addr)? {
+            b"RSD PTR " => *b"RSDP",
+            v => [v[0], v[1], v[2], v[3]],
+        };
+        writeln!(out, "@ACPI TABLE {:02x}{:02x}{:02x}{:02x} {:x} {:x}", signature[0],
+                 signature[1], signature[2], signature[3], addr, length)?;
+
+        let mut hash = FNV_OFFSET;
+        let mut buf  = [0u8; BYTES_PER_LINE];
+        for off in (0..length as usize).step_by(BYTES_PER_LINE) {
+            let line = &mut buf[..core::cmp::min(BYTES_PER_LINE, length as usize - off)];
+            mem.read_bytes(addr + off as u64, line)?;
+
+            write!(out, "@ACPI DATA ")?;
+            for &byte in line.iter() {
+                write!(out, "{:02x}", byte)?;
+                hash = fnv1a(hash, byte);
+            }
+            writeln!(out)?;
+        }
+        writeln!(out, "@ACPI END {:08x}", hash)?;
+        num_tables += 1;
+    }
+    writeln!(out, "@ACPI DONE {:x}", num_tables)?;
+    Ok(())
+}
+
+/// Initial state of the 32-bit FNV-1a hash
+const FNV_OFFSET: u32 = 0x811c_9dc5;
+
+/// Feed `byte` into the FNV-1a `hash`
+fn fnv1a(hash: u32, byte: u8) -> u32 {
+    (hash ^ byte as u32).wrapping_mul(0x0100_0193)
+}
@@ -0,0 +1,283 @@
+//! FADT - Fixed ACPI Description Table
+//!     - Describes the fixed hardware registers used for power management, and points to the DSDT
+//!     - Provides `reboot` and `shutdown`, the sleep type needed for the S5 (soft-off) state is
+//!       pulled out of the DSDT with a minimal scan of its AML
+//!     - https://wiki.osdev.org/FADT
+
+use crate::{println, physmem::PhysMem};
+use super::{Result, Error, tables::TableInfo, gas::GenericAddress};
+
+use spin::Mutex;
+use x86::io;
+
+/// Power management registers of the running system, used by `reboot` and `shutdown`
+static POWER: Mutex<Option<Fadt>> = Mutex::new(None);
+
+/// Length of the acpi 1.0 FADT, later revisions only append fields
+const MIN_FADT_LENGTH: u32 = 116;
+
+/// The reset register is supported
+const RESET_REG_SUP: u32 = 1 << 10;
+
+/// An 8042 keyboard controller is present
+const IAPC_8042: u16 = 1 << 1;
+
+/// Bits of the PM1 control register
+const SCI_EN:  u16 = 1 << 0;
+const SLP_EN:  u16 = 1 << 13;
+const SLP_TYP: u16 = 0b111 << 10;
+
+/// AML opcodes used while scanning for the `\_S5` package
+const NAME_OP:      u8 = 0x08;
+const PACKAGE_OP:   u8 = 0x12;
+const ZERO_OP:      u8 = 0x00;
+const ONE_OP:       u8 = 0x01;
+const BYTE_PREFIX:  u8 = 0x0a;
+const WORD_PREFIX:  u8 = 0x0b;
+const DWORD_PREFIX: u8 = 0x0c;
+const ONES_OP:      u8 = 0xff;
+
+/// Everything we parsed out of the FADT
+#[derive(Debug, Default, Copy, Clone)]
+pub struct Fadt {
+    /// Physical address of the DSDT
+    pub dsdt:        u64,
+
+    /// Io port used to switch the system from legacy into acpi mode
+    pub smi_cmd:     u32,
+
+    /// Value to write to `smi_cmd` to enable acpi mode
+    pub acpi_enable: u8,
+
+    /// PM1 control registers, the b block is optional
+    pub pm1a_cnt:    GenericAddress,
+    pub pm1b_cnt:    GenericAddress,
+
+    /// Register to write `reset_value` to in order to reset the system, if supported
+    pub reset_reg:   Option<GenericAddress>,
+    pub reset_value: u8,
+
+    /// An 8042 keyboard controller is present, it can pulse the reset line
+    pub has_8042:    bool,
+
+    /// `SLP_TYPa` and `SLP_TYPb` values for the S5 state, taken from the DSDT
+    pub s5:          Option<(u8, u8)>,
+}
+
+impl Fadt {
+    /// Parse out the FADT described by `table`
+    pub fn parse<M: PhysMem>(mem: &M, table: &TableInfo) -> Result<Self> {
+        if table.length < MIN_FADT_LENGTH {
+            return Err(Error::InvalidFADTLength);
+        }
+        let addr = table.addr;
+
+        let mut fadt = Self {
+            dsdt:        mem.read::<u32>(addr + 40)? as u64,
+            smi_cmd:     mem.read(addr + 48)?,
+            acpi_enable: mem.read(addr + 52)?,
+            pm1a_cnt:    GenericAddress::io(mem.read(addr + 64)?, 16),
+            pm1b_cnt:    GenericAddress::io(mem.read(addr + 68)?, 16),
+            ..Default::default()
+        };
+
+        // Fields added in acpi 2.0
+        if table.length >= 129 {
+            let flags: u32 = mem.read(addr + 112)?;
+            if (flags & RESET_REG_SUP) != 0 {
+                fadt.reset_reg   = Some(mem.read(addr + 116)?);
+                fadt.reset_value = mem.read(addr + 128)?;
+            }
+            fadt.has_8042 = (mem.read::<u16>(addr + 109)? & IAPC_8042) != 0;
+        } else {
+            // Acpi 1.0 systems are old enough to always have one
+            fadt.has_8042 = true;
+        }
+
+        // The extended fields take precedence over the legacy ones if they are filled in
+        if table.length >= 148 {
+            let x_dsdt: u64 = mem.read(addr + 140)?;
+            if x_dsdt != 0 {
+                fadt.dsdt = x_dsdt;
+            }
+        }
+        if table.length >= 196 {
+            let x_pm1a_cnt: GenericAddress = mem.read(addr + 172)?;
+            let x_pm1b_cnt: GenericAddress = mem.read(addr + 184)?;
+            if x_pm1a_cnt.is_present() {
+                fadt.pm1a_cnt = x_pm1a_cnt;
+            }
+            if x_pm1b_cnt.is_present() {
+                fadt.pm1b_cnt = x_pm1b_cnt;
+            }
+        }
+        Ok(fadt)
+    }
+
+    /// Make this the FADT used by `reboot` and `shutdown`
+    pub fn install(self) {
+        *POWER.lock() = Some(self);
+    }
+}
+
+/// Find the `\_S5` package in the AML of the DSDT and return its `SLP_TYPa` and `SLP_TYPb` values.
+/// This is not an AML interpreter, it only understands the encoding firmware uses in practice:
+/// `Name(\_S5, Package() { a, b, ... })` with integer constants
+pub fn parse_s5<M: PhysMem>(mem: &M, dsdt: &TableInfo) -> Result<Option<(u8, u8)>> {
+    let start_addr = dsdt.payload();
+    let end_addr   = dsdt.end();
+
+    if end_addr - start_addr < 4 {
+        return Ok(None);
+    }
+
+    for addr in start_addr..=end_addr - 4 {
+        if &mem.read::<[u8; 4]>(addr)? != b"_S5_" {
+            continue;
+        }
+
+        // A name at the very end of the table can't be followed by a package
+        if addr + 6 >= end_addr {
+            continue;
+        }
+
+        // The name has to be defined through a NameOp, optionally with a root prefix
+        let name_op = match mem.read::<u8>(addr - 1)? {
+            b'\\' => mem.read::<u8>(addr - 2)?,
+            v => v,
+        };
+        if name_op != NAME_OP || mem.read::<u8>(addr + 4)? != PACKAGE_OP {
+            continue;
+        }
+
+        // Skip the PkgLength, the top 2 bits of its lead byte encode the number of extra bytes
+        let mut ptr = addr + 5;
+        let lead: u8 = mem.read(ptr)?;
+        ptr += 1 + (lead >> 6) as u64;
+
+        let num_elements: u8 = mem.read(ptr)?;
+        ptr += 1;
+
+        let slp_typa = aml_integer(mem, &mut ptr, end_addr)?;
+        let slp_typb = match num_elements {
+            0 | 1 => Some(0),
+            _ => aml_integer(mem, &mut ptr, end_addr)?,
+        };
+        return match (slp_typa, slp_typb) {
+            (Some(a), Some(b)) => Ok(Some((a, b))),
+            _ => Err(Error::InvalidS5Package),
+        };
+    }
+    Ok(None)
+}
+
+/// Read an integer constant at `ptr` and advance past it. Sleep types are only 3 bits wide, so
+/// only the low byte is kept
+fn aml_integer<M: PhysMem>(mem: &M, ptr: &mut u64, end_addr: u64) -> Result<Option<u8>> {
+    if *ptr >= end_addr {
+        return Ok(None);
+    }
+
+    let op: u8 = mem.read(*ptr)?;
+    let (value, len) = match op {
+        ZERO_OP      => (0, 1),
+        ONE_OP       => (1, 1),
+        ONES_OP      => (0xff, 1),
+        BYTE_PREFIX  => (mem.read::<u8>(*ptr + 1)?, 2),
+        WORD_PREFIX  => (mem.read::<u8>(*ptr + 1)?, 3),
+        DWORD_PREFIX => (mem.read::<u8>(*ptr + 1)?, 5),
+        _ => return Ok(None),
+    };
+
+    // Constants cut off by the end of the table are as good as missing
+    if *ptr + len > end_addr {
+        return Ok(None);
+    }
+    *ptr += len;
+    Ok(Some(value))
+}
+
+/// Reset the system. Tries the acpi reset register first, then pulses the reset line through the
+/// 8042 keyboard controller, and forces a triple fault if neither worked
+pub fn reboot() -> ! {
+    let fadt = *POWER.lock();
+
+    unsafe {
+        if let Some(fadt) = fadt {
+            if let Some(reset_reg) = fadt.reset_reg {
+                reset_reg.write(fadt.reset_value as u64);
+                delay();
+            }
+        }
+
+        if fadt.is_none_or(|f| f.has_8042) {
+            // Wait for the input buffer to drain before sending the reset command
+            for _ in 0..0x10000 {
+                if (io::inb(0x64) & 0b10) == 0 {
+                    break;
+                }
+            }
+            io::outb(0x64, 0xfe);
+            delay();
+        }
+
+        // Any exception with an empty idt escalates into a triple fault
+        let idt = [0u8; 10];
+        core::arch::asm!(
+            "lidt [{idt}]",
+            "int3",
+            idt = in(reg) idt.as_ptr(),
+        );
+    }
+    halt()
+}
+
+/// Power off the system by entering the S5 sleep state. If the firmware does not describe how
+/// to do that, the core is halted instead
+pub fn shutdown() -> ! {
+    let fadt = *POWER.lock();
+
+    match fadt {
+        Some(Fadt { s5: Some((slp_typa, slp_typb)), pm1a_cnt, pm1b_cnt, smi_cmd, acpi_enable, .. })
+            if pm1a_cnt.is_present() => unsafe {
+            // The sleep registers only work once the system switched into acpi mode
+            if (pm1a_cnt.read() as u16 & SCI_EN) == 0 && smi_cmd != 0 && acpi_enable != 0 {
+                io::outb(smi_cmd as u16, acpi_enable);
+                for _ in 0..0x100000 {
+                    if (pm1a_cnt.read() as u16 & SCI_EN) != 0 {
+                        break;
+                    }
+                    core::hint::spin_loop();
+                }
+            }
+
+            let sleep = |reg: GenericAddress, slp_typ: u8| {
+                let value = (reg.read() as u16 & !SLP_TYP) | ((slp_typ as u16) << 10) | SLP_EN;
+                reg.write(value as u64);
+            };
+            sleep(pm1a_cnt, slp_typa);
+            if pm1b_cnt.is_present() {
+                sleep(pm1b_cnt, slp_typb);
+            }
+            delay();
+        }
+        _ => {}
+    }
+
+    println!("Acpi shutdown is not supported, halting");
+    halt()
+}
+
+/// Give the hardware some time to act on a request before trying the next method
+fn delay() {
+    for _ in 0..0x1000000 {
+        core::hint::spin_loop();
+    }
+}
+
+/// Halt the core with interrupts disabled
+fn halt() -> ! {
+    loop {
+        unsafe { core::arch::asm!("cli", "hlt") };
+    }
+}
@@ -0,0 +1,101 @@
+//! Generic Address Structure
+//!     - Used by acpi tables to describe the location of a register, which can live in memory, in
+//!       io space or in pci configuration space
+
+use crate::{
+    physmem::Pod,
+    pci::{LegacyPci, PciAddress, PciConfig},
+};
+
+use x86::io;
+
+/// Register lives in physical memory
+pub const SYSTEM_MEMORY: u8 = 0;
+
+/// Register lives in io port space
+pub const SYSTEM_IO: u8 = 1;
+
+/// Register lives in the pci configuration space of a device on bus 0
+pub const PCI_CONFIG: u8 = 2;
+
+/// Location of a register
+#[derive(Debug, Default, Copy, Clone)]
+#[repr(C, packed)]
+pub struct GenericAddress {
+    pub address_space: u8,
+    pub bit_width:     u8,
+    pub bit_offset:    u8,
+    pub access_size:   u8,
+    pub address:       u64,
+}
+
+unsafe impl Pod for GenericAddress {}
+
+impl GenericAddress {
+    /// Register of `bit_width` bits at io port `port`, used for the legacy 32-bit table fields
+    pub fn io(port: u32, bit_width: u8) -> Self {
+        Self { address_space: SYSTEM_IO, bit_width, bit_offset: 0, access_size: 0,
+               address: port as u64 }
+    }
+
+    /// Whether this describes an actual register
+    pub fn is_present(&self) -> bool {
+        self.address != 0
+    }
+
+    /// Size of a single access in bytes. Prefer the access size, older tables leave it undefined
+    fn access_bytes(&self) -> u8 {
+        match self.access_size {
+            1..=4 => 1 << (self.access_size - 1),
+            _ => core::cmp::max(1, self.bit_width / 8),
+        }
+    }
+
+    /// Read the register
+    ///
+    /// # Safety
+    /// The register has to be accessible, memory mapped registers identity mapped
+    pub unsafe fn read(&self) -> u64 {
+        let addr = self.address;
+        match (self.address_space, self.access_bytes()) {
+            (SYSTEM_MEMORY, 1) => core::ptr::read_volatile(addr as *const u8) as u64,
+            (SYSTEM_MEMORY, 2) => core::ptr::read_volatile(addr as *const u16) as u64,
+            (SYSTEM_MEMORY, 4) => core::ptr::read_volatile(addr as *const u32) as u64,
+            (SYSTEM_MEMORY, _) => core::ptr::read_volatile(addr as *const u64),
+            (SYSTEM_IO, 1)     => io::inb(addr as u16) as u64,
+            (SYSTEM_IO, 2)     => io::inw(addr as u16) as u64,
+            (SYSTEM_IO, _)     => io::inl(addr as u16) as u64,
+            _ => 0,
+        }
+    }
+
+    /// Write `value` to the register
+    ///
+    /// # Safety
+    /// The register has to be accessible, memory mapped registers identity mapped. Writing to
+    /// hardware registers can have arbitrary side effects
+    pub unsafe fn write(&self, value: u64) {
+        let addr = self.address;
+        match (self.address_space, self.access_bytes()) {
+            (SYSTEM_MEMORY, 1) => core::ptr::write_volatile(addr as *mut u8, value as u8),
+            (SYSTEM_MEMORY, 2) => core::ptr::write_volatile(addr as *mut u16, value as u16),
+            (SYSTEM_MEMORY, 4) => core::ptr::write_volatile(addr as *mut u32, value as u32),
+            (SYSTEM_MEMORY, _) => core::ptr::write_volatile(addr as *mut u64, value),
+            (SYSTEM_IO, 1)     => io::outb(addr as u16, value as u8),
+            (SYSTEM_IO, 2)     => io::outw(addr as u16, value as u16),
+            (SYSTEM_IO, _)     => io::outl(addr as u16, value as u32),
+            (PCI_CONFIG, _)    => {
+                // Device in bits 32-47, function in bits 16-31 and the register offset in the low
+                // bits, always on bus 0. Only byte writes are used for this in practice
+                let pci_addr = PciAddress {
+                    segment:  0,
+                    bus:      0,
+                    device:   (addr >> 32) as u8,
+                    function: (addr >> 16) as u8,
+                };
+                let _ = LegacyPci.write8(pci_addr, addr as u16, value as u8);
+            }
+            _ => {},
+        }
+    }
+}
@@ -0,0 +1,47 @@
+//! HPET - High Precision Event Timer description table
+//!     - Locates the registers of the HPET, the timer itself is driven by `crate::hpet`
+
+use crate::physmem::PhysMem;
+use super::{Result, Error, tables::TableInfo, gas::{self, GenericAddress}};
+
+/// Length of the HPET table
+const HPET_LENGTH: u32 = 56;
+
+/// Everything we parsed out of the HPET table
+#[derive(Debug, Default, Copy, Clone)]
+pub struct HpetInfo {
+    /// Physical address of the HPET registers
+    pub addr:        u64,
+
+    /// Sequence number of this HPET block
+    pub number:      u8,
+
+    /// Number of comparators implemented by the block
+    pub comparators: u8,
+
+    /// Smallest tick count a periodic timer can be programmed with without losing interrupts
+    pub min_tick:    u16,
+}
+
+impl HpetInfo {
+    /// Parse out the HPET table described by `table`
+    pub fn parse<M: PhysMem>(mem: &M, table: &TableInfo) -> Result<Self> {
+        if table.length < HPET_LENGTH {
+            return Err(Error::InvalidHPETTable);
+        }
+        let addr = table.addr;
+
+        // The registers are always memory mapped
+        let regs: GenericAddress = mem.read(addr + 40)?;
+        if regs.address_space != gas::SYSTEM_MEMORY || !regs.is_present() {
+            return Err(Error::InvalidHPETTable);
+        }
+
+        Ok(Self {
+            addr:        regs.address,
+            number:      mem.read(addr + 52)?,
+            comparators: (mem.read::<u8>(addr + 37)? & 0x1f) + 1,
+            min_tick:    mem.read(addr + 53)?,
+        })
+    }
+}
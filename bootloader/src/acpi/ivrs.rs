@@ -0,0 +1,203 @@
+//! IVRS - I/O Virtualization Reporting Structure (AMD-Vi)
+//!     - Describes the IOMMUs (IVHD blocks) of the system and which devices sit behind each one
+//!     - Memory definitions (IVMD) describe ranges devices keep using for DMA behind the os' back,
+//!       they must never be handed out
+
+use crate::physmem::PhysMem;
+use super::{Result, Error, tables::TableInfo};
+
+use alloc::vec::Vec;
+
+/// IVHD block types, they only differ in the size of their header
+const IVHD_LEGACY: u8 = 0x10;
+const IVHD_EFR:    u8 = 0x11;
+const IVHD_MIXED:  u8 = 0x40;
+
+/// IVMD block types
+const IVMD_ALL:    u8 = 0x20;
+const IVMD_SELECT: u8 = 0x21;
+const IVMD_RANGE:  u8 = 0x22;
+
+/// IVHD device entry types
+const DEV_ALL:               u8 = 0x01;
+const DEV_SELECT:            u8 = 0x02;
+const DEV_RANGE_START:       u8 = 0x03;
+const DEV_RANGE_END:         u8 = 0x04;
+const DEV_ALIAS_SELECT:      u8 = 0x42;
+const DEV_ALIAS_RANGE_START: u8 = 0x43;
+const DEV_EXT_SELECT:        u8 = 0x46;
+const DEV_EXT_RANGE_START:   u8 = 0x47;
+const DEV_SPECIAL:           u8 = 0x48;
+const DEV_ACPI_HID:          u8 = 0xf0;
+
+/// An IOMMU
+#[derive(Debug, Clone)]
+pub struct Ivhd {
+    /// Pci device id (bus/device/function) of the IOMMU itself
+    pub device_id: u16,
+
+    /// Physical address of the IOMMU's registers
+    pub base:      u64,
+    pub segment:   u16,
+
+    /// Covers every device of `segment`
+    pub all:       bool,
+
+    /// Inclusive ranges of pci device ids behind this IOMMU
+    pub devices:   Vec<(u16, u16)>,
+}
+
+/// A memory range that the devices `devices` keep using for DMA
+#[derive(Debug, Clone)]
+pub struct Ivmd {
+    /// Physical range `[start, end)`
+    pub start:   u64,
+    pub end:     u64,
+
+    /// Inclusive range of pci device ids this applies to, all devices if `None`
+    pub devices: Option<(u16, u16)>,
+}
+
+/// Everything we parsed out of the IVRS
+#[derive(Debug, Default, Clone)]
+pub struct Ivrs {
+    /// Virtualization info, contains the supported address sizes
+    pub info:     u32,
+    pub units:    Vec<Ivhd>,
+    pub reserved: Vec<Ivmd>,
+}
+
+impl Ivrs {
+    /// Parse out the IVRS described by `table`
+    pub fn parse<M: PhysMem>(mem: &M, table: &TableInfo) -> Result<Self> {
+        let mut ivrs = Self {
+            info: mem.read(table.payload())?,
+            ..Default::default()
+        };
+
+        for_each_block(mem, table, |typ, ptr, end| {
+            match typ {
+                IVHD_LEGACY | IVHD_EFR | IVHD_MIXED => {
+                    let header_len = if typ == IVHD_LEGACY { 24 } else { 40 };
+                    if end - ptr < header_len {
+                        return Err(Error::InvalidIVRSBlock);
+                    }
+
+                    let mut ivhd = Ivhd {
+                        device_id: mem.read(ptr + 4)?,
+                        base:      mem.read(ptr + 8)?,
+                        segment:   mem.read(ptr + 16)?,
+                        all:       false,
+                        devices:   Vec::new(),
+                    };
+                    parse_devices(mem, &mut ivhd, ptr + header_len, end)?;
+                    ivrs.units.push(ivhd);
+                }
+                IVMD_ALL | IVMD_SELECT | IVMD_RANGE => {
+                    let (start, ivmd_end) = ivmd_range(mem, ptr, end)?;
+                    let device_id: u16 = mem.read(ptr + 4)?;
+                    let aux:       u16 = mem.read(ptr + 6)?;
+                    ivrs.reserved.push(Ivmd {
+                        start,
+                        end:     ivmd_end,
+                        devices: match typ {
+                            IVMD_ALL    => None,
+                            IVMD_SELECT => Some((device_id, device_id)),
+                            _           => Some((device_id, aux)),
+                        },
+                    });
+                }
+                _ => {},
+            }
+            Ok(())
+        })?;
+        Ok(ivrs)
+    }
+}
+
+/// Call `f` with the range `[start, end)` of every IVMD in the IVRS described by `table`. Nothing
+/// is allocated, so this can run before the heap is usable
+pub fn for_each_reserved<M: PhysMem>(mem: &M, table: &TableInfo, mut f: impl FnMut(u64, u64))
+        -> Result<()> {
+    for_each_block(mem, table, |typ, ptr, end| {
+        if matches!(typ, IVMD_ALL | IVMD_SELECT | IVMD_RANGE) {
+            let (start, end) = ivmd_range(mem, ptr, end)?;
+            f(start, end);
+        }
+        Ok(())
+    })
+}
+
+/// Call `f` with the type and range `[ptr, end)` of every block in the IVRS described by `table`
+fn for_each_block<M: PhysMem>(mem: &M, table: &TableInfo,
+                              mut f: impl FnMut(u8, u64, u64) -> Result<()>) -> Result<()> {
+    // Blocks follow 8 reserved bytes
+    let mut ptr = table.payload() + 12;
+    while ptr + 4 <= table.end() {
+        let typ: u8  = mem.read(ptr)?;
+        let len: u16 = mem.read(ptr + 2)?;
+        if len < 4 || ptr + len as u64 > table.end() {
+            return Err(Error::InvalidIVRSBlock);
+        }
+        let end = ptr + len as u64;
+        f(typ, ptr, end)?;
+        ptr = end;
+    }
+    Ok(())
+}
+
+/// Physical range `[start, end)` of the IVMD block in `[ptr, end)`
+fn ivmd_range<M: PhysMem>(mem: &M, ptr: u64, end: u64) -> Result<(u64, u64)> {
+    if end - ptr < 32 {
+        return Err(Error::InvalidIVRSBlock);
+    }
+
+    let start: u64 = mem.read(ptr + 16)?;
+    let size:  u64 = mem.read(ptr + 24)?;
+    Ok((start, start.saturating_add(size)))
+}
+
+/// Parse the device entries of an IVHD block in `[ptr, end)`
+fn parse_devices<M: PhysMem>(mem: &M, ivhd: &mut Ivhd, mut ptr: u64, end: u64) -> Result<()> {
+    // Start of a range whose end entry has not been seen yet
+    let mut range_start = None;
+
+    while ptr + 4 <= end {
+        let typ: u8 = mem.read(ptr)?;
+        let len = match typ {
+            // Variable length, the uid follows a fixed part of 22 bytes
+            DEV_ACPI_HID => 22 + mem.read::<u8>(ptr + 21)? as u64,
+            0x00..=0x3f  => 4,
+            0x40..=0x7f  => 8,
+            0x80..=0xbf  => 16,
+            _            => 32,
+        };
+        if ptr + len > end {
+            return Err(Error::InvalidIVRSBlock);
+        }
+
+        let device_id: u16 = mem.read(ptr + 1)?;
+        match typ {
+            DEV_ALL => ivhd.all = true,
+            DEV_SELECT | DEV_ALIAS_SELECT | DEV_EXT_SELECT => {
+                ivhd.devices.push((device_id, device_id));
+            }
+            DEV_RANGE_START | DEV_ALIAS_RANGE_START | DEV_EXT_RANGE_START => {
+                range_start = Some(device_id);
+            }
+            DEV_RANGE_END => {
+                if let Some(start) = range_start.take() {
+                    ivhd.devices.push((start, device_id));
+                }
+            }
+            DEV_SPECIAL => {
+                // I/O APICs and HPETs, the device id sits after the handle
+                let device_id: u16 = mem.read(ptr + 5)?;
+                ivhd.devices.push((device_id, device_id));
+            }
+            _ => {},
+        }
+        ptr += len;
+    }
+    Ok(())
+}
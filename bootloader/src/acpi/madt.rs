@@ -0,0 +1,269 @@
+//! MADT - Multiple APIC Description Table
+//!     - Lists all processors and their local APICs, the I/O APICs, and how legacy irqs and NMIs
+//!       are wired up to them
+//!     - https://wiki.osdev.org/MADT
+
+use crate::{config, physmem::PhysMem};
+use super::{Result, Error, tables::TableInfo};
+
+use alloc::vec::Vec;
+
+/// The system also has a legacy 8259 PIC pair that needs to be masked off
+const PCAT_COMPAT: u32 = 1 << 0;
+
+/// Processor is enabled
+const PROCESSOR_ENABLED: u32 = 1 << 0;
+
+/// Processor is disabled but can be enabled by the os
+const ONLINE_CAPABLE: u32 = 1 << 1;
+
+/// Processor uid used by NMI entries that apply to all processors
+pub const ALL_PROCESSORS: u32 = 0xffff_ffff;
+
+/// MPS INTI flags describing the polarity and trigger mode of an interrupt
+#[derive(Debug, Clone, Copy)]
+pub struct MpsFlags(pub u16);
+
+impl MpsFlags {
+    /// Interrupt is active low, otherwise active high or conforming to the bus
+    pub fn active_low(&self) -> bool {
+        self.0 & 0b11 == 0b11
+    }
+
+    /// Interrupt is level triggered, otherwise edge triggered or conforming to the bus
+    pub fn level_triggered(&self) -> bool {
+        (self.0 >> 2) & 0b11 == 0b11
+    }
+}
+
+/// A processor and its local APIC
+#[derive(Debug, Clone, Copy)]
+pub struct Processor {
+    /// Id of the local APIC, full 32 bits for x2APIC entries
+    pub apic_id:        u32,
+
+    /// ACPI processor uid, this is what NMI entries and the namespace refer to
+    pub uid:            u32,
+
+    /// Processor is enabled and can be started right away
+    pub enabled:        bool,
+
+    /// Processor is disabled, but the hardware supports enabling it while the system is running
+    pub online_capable: bool,
+
+    /// This is the processor that runs the bootloader
+    pub bsp:            bool,
+}
+
+/// An I/O APIC and the global system interrupts it handles
+#[derive(Debug, Clone, Copy)]
+pub struct IoApic {
+    pub id:       u8,
+    pub addr:     u32,
+    pub gsi_base: u32,
+}
+
+/// A legacy isa irq that is not identity mapped to a global system interrupt
+#[derive(Debug, Clone, Copy)]
+pub struct InterruptOverride {
+    pub bus:    u8,
+    pub source: u8,
+    pub gsi:    u32,
+    pub flags:  MpsFlags,
+}
+
+/// A global system interrupt that is wired up as an NMI
+#[derive(Debug, Clone, Copy)]
+pub struct NmiSource {
+    pub gsi:   u32,
+    pub flags: MpsFlags,
+}
+
+/// Local APIC interrupt line (LINT0/LINT1) that is wired up as an NMI
+#[derive(Debug, Clone, Copy)]
+pub struct LocalNmi {
+    /// ACPI uid of the processor this applies to, or `ALL_PROCESSORS`
+    pub processor: u32,
+    pub lint:      u8,
+    pub flags:     MpsFlags,
+}
+
+/// Everything we parsed out of the MADT
+#[derive(Debug, Default)]
+pub struct Madt {
+    /// Physical address of the local APICs, after the 64-bit override has been applied
+    pub local_apic_addr: u64,
+
+    /// A legacy PIC is present and has to be disabled before using the APICs
+    pub pcat_compat: bool,
+
+    /// All processors that are enabled or can be enabled
+    pub processors: Vec<Processor>,
+
+    pub io_apics:    Vec<IoApic>,
+    pub overrides:   Vec<InterruptOverride>,
+    pub nmi_sources: Vec<NmiSource>,
+    pub local_nmis:  Vec<LocalNmi>,
+}
+
+impl Madt {
+    /// Parse out the MADT described by `table`. Entries that are too short are skipped and
+    /// recorded in `warnings`
+    pub fn parse<M: PhysMem>(mem: &M, table: &TableInfo, warnings: &mut Vec<Error>)
+            -> Result<Self> {
+        let start_addr = table.payload();
+        let end_addr   = table.end();
+
+        let mut madt = Self {
+            local_apic_addr: mem.read::<u32>(start_addr)? as u64,
+            pcat_compat:     (mem.read::<u32>(start_addr + 4)? & PCAT_COMPAT) != 0,
+            ..Default::default()
+        };
+
+        let mut records_ptr = start_addr + 8;
+        loop {
+            if (records_ptr + 2) > end_addr {
+                break;
+            }
+            let entry_type: u8 = mem.read(records_ptr)?;
+            let entry_len:  u8 = mem.read(records_ptr + 1)?;
+
+            // Out of range for madt table, or an entry that would never advance the loop
+            if (records_ptr + entry_len as u64) > end_addr || entry_len < 2 {
+                break;
+            }
+
+            // Size every entry type is required to have
+            let expected_len = match entry_type {
+                0 => 8,
+                1 => 12,
+                2 => 10,
+                3 => 8,
+                4 => 6,
+                5 => 12,
+                9 => 16,
+                10 => 12,
+                _ => entry_len,
+            };
+            // Later revisions may append fields, but a short entry can't be parsed. Skipping it
+            // only loses that one entry
+            if entry_len < expected_len {
+                warnings.push(Error::InvalidMADTEntrySize { entry_type, entry_len });
+                records_ptr += entry_len as u64;
+                continue;
+            }
+
+            match entry_type {
+                0 => {
+                    // Processor Local APIC - Single logical processor + its local
+                    // interrupt controller
+                    let proc_id: u8 = mem.read(records_ptr + 2)?;
+                    let apic_id: u8 = mem.read(records_ptr + 3)?;
+                    let flags:  u32 = mem.read(records_ptr + 4)?;
+
+                    madt.add_processor(apic_id as u32, proc_id as u32, flags);
+                },
+                1 => {
+                    // I/O APIC
+                    madt.io_apics.push(IoApic {
+                        id:       mem.read(records_ptr + 2)?,
+                        addr:     mem.read(records_ptr + 4)?,
+                        gsi_base: mem.read(records_ptr + 8)?,
+                    });
+                },
+                2 => {
+                    // Interrupt Source Override
+                    madt.overrides.push(InterruptOverride {
+                        bus:    mem.read(records_ptr + 2)?,
+                        source: mem.read(records_ptr + 3)?,
+                        gsi:    mem.read(records_ptr + 4)?,
+                        flags:  MpsFlags(mem.read(records_ptr + 8)?),
+                    });
+                },
+                3 => {
+                    // Non-maskable Interrupt Source
+                    madt.nmi_sources.push(NmiSource {
+                        flags: MpsFlags(mem.read(records_ptr + 2)?),
+                        gsi:   mem.read(records_ptr + 4)?,
+                    });
+                },
+                4 => {
+                    // Local APIC NMI, a processor id of 0xff means all processors
+                    let processor = match mem.read::<u8>(records_ptr + 2)? {
+                        0xff => ALL_PROCESSORS,
+                        v => v as u32,
+                    };
+                    madt.local_nmis.push(LocalNmi {
+                        processor,
+                        flags:     MpsFlags(mem.read(records_ptr + 3)?),
+                        lint:      mem.read(records_ptr + 5)?,
+                    });
+                },
+                5 => {
+                    // Local APIC Address Override - 64-bit address of the local APICs
+                    madt.local_apic_addr = mem.read(records_ptr + 4)?;
+                },
+                9 => {
+                    // Processor Local x2APIC - Single physical processor + its local x2APIC
+                    // Pretty much identical to Local APIC, just used when core numbers are
+                    // required that that don't fit into a u8
+                    let apic_id: u32 = mem.read(records_ptr + 4)?;
+                    let flags:   u32 = mem.read(records_ptr + 8)?;
+                    let acpi_id: u32 = mem.read(records_ptr + 12)?;
+
+                    madt.add_processor(apic_id, acpi_id, flags);
+                },
+                10 => {
+                    // Local x2APIC NMI
+                    madt.local_nmis.push(LocalNmi {
+                        flags:     MpsFlags(mem.read(records_ptr + 2)?),
+                        processor: mem.read(records_ptr + 4)?,
+                        lint:      mem.read(records_ptr + 8)?,
+                    });
+                },
+                _ => {},
+            }
+            records_ptr += entry_len as u64;
+        }
+        Ok(madt)
+    }
+
+    /// Record a processor if it is enabled/can be enabled
+    fn add_processor(&mut self, apic_id: u32, uid: u32, flags: u32) {
+        let enabled        = (flags & PROCESSOR_ENABLED) != 0;
+        let online_capable = (flags & ONLINE_CAPABLE) != 0;
+        if enabled || online_capable {
+            self.processors.push(Processor { apic_id, uid, enabled, online_capable, bsp: false });
+        }
+    }
+
+    /// Mark the processor with `apic_id` as the BSP. The parser can't tell which processor is
+    /// running it, so this is up to the caller
+    pub fn set_bsp(&mut self, apic_id: u32) {
+        for p in &mut self.processors {
+            p.bsp = p.apic_id == apic_id;
+        }
+    }
+
+    /// Processors that should be brought up. These are all enabled processors, or only the ones
+    /// requested in `config::CORES`, limited to the first `config::MAX_CORES` of them. The BSP is
+    /// already running, so it is always included and takes up one of the slots
+    pub fn boot_processors(&self) -> impl Iterator<Item = &Processor> {
+        let mut remaining = config::MAX_CORES.unwrap_or(usize::MAX).saturating_sub(1);
+        self.processors.iter().filter(move |p| {
+            if p.bsp {
+                return true;
+            }
+
+            let wanted = match config::CORES {
+                Some(cores) => cores.contains(&p.apic_id),
+                None => p.enabled,
+            };
+            if !wanted || remaining == 0 {
+                return false;
+            }
+            remaining -= 1;
+            true
+        })
+    }
+}
@@ -0,0 +1,56 @@
+//! MCFG - PCI Express memory mapped configuration space table
+//!     - Lists the ECAM regions through which the configuration space of every pci segment and bus
+//!       range can be accessed
+
+use crate::physmem::PhysMem;
+use super::{Result, tables::TableInfo};
+
+use alloc::vec::Vec;
+
+/// Size of a single entry in the MCFG
+const ENTRY_SIZE: u64 = 16;
+
+/// Configuration space of buses `start_bus..=end_bus` of `segment`. `base` is where bus 0 of the
+/// segment would be, even if the region starts at a later bus
+#[derive(Debug, Clone, Copy)]
+pub struct EcamRegion {
+    pub base:      u64,
+    pub segment:   u16,
+    pub start_bus: u8,
+    pub end_bus:   u8,
+}
+
+impl EcamRegion {
+    /// Physical address of the configuration space of `bus`, every bus takes up 1MiB
+    pub fn bus_addr(&self, bus: u8) -> u64 {
+        self.base + ((bus as u64) << 20)
+    }
+
+    /// Physical address right after the configuration space of `end_bus`
+    pub fn end(&self) -> u64 {
+        self.base + ((self.end_bus as u64 + 1) << 20)
+    }
+}
+
+/// Parse out all ECAM regions listed in the MCFG described by `table`
+pub fn parse<M: PhysMem>(mem: &M, table: &TableInfo) -> Result<Vec<EcamRegion>> {
+    let mut regions = Vec::new();
+
+    // Entries follow 8 reserved bytes after the header
+    let mut entry = table.payload() + 8;
+    while entry + ENTRY_SIZE <= table.end() {
+        let region = EcamRegion {
+            base:      mem.read(entry)?,
+            segment:   mem.read(entry + 8)?,
+            start_bus: mem.read(entry + 10)?,
+            end_bus:   mem.read(entry + 11)?,
+        };
+
+        // Skip entries that can't describe any bus
+        if region.base != 0 && region.start_bus <= region.end_bus {
+            regions.push(region);
+        }
+        entry += ENTRY_SIZE;
+    }
+    Ok(regions)
+}
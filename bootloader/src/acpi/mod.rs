@@ -0,0 +1,632 @@
+//! ACPI - Advanced Configuration and Power Interface
+//!     - This can be used for general power management and to manage peripherals
+
+pub mod tables;
+pub mod madt;
+pub mod fadt;
+pub mod gas;
+pub mod hpet;
+pub mod mcfg;
+pub mod dmar;
+pub mod ivrs;
+pub mod dump;
+
+#[cfg(test)]
+mod tests;
+
+use crate::{
+    config,
+    physmem::{self, Pod, PhysMem},
+};
+
+use core::mem::size_of;
+use alloc::vec::Vec;
+use either::Either;
+use tables::{TableInfo, TableRegistry};
+use madt::Madt;
+use fadt::Fadt;
+use hpet::HpetInfo;
+use mcfg::EcamRegion;
+use dmar::Dmar;
+use ivrs::Ivrs;
+
+pub use fadt::{reboot, shutdown};
+
+/// Upper bound for the length of the extended RSDP, anything larger is considered corrupted
+const MAX_RSDP_LENGTH: usize = 0x1000;
+
+pub type Result<T> = core::result::Result<T, Error>;
+#[derive(Debug)]
+pub enum Error {
+    /// Was unable to find the `RSDP PTR ` signature while iterating through the relevant regions
+    RSDPSignatureNotFound,
+
+    /// Invalid RSDP Version found, `1` does not exist
+    InvalidVersion,
+
+    /// The length of the extended RSDP is smaller than the structure or unreasonably large
+    InvalidRSDPLength,
+
+    /// RSDP Checksum check failed
+    RSDPChecksum,
+
+    /// RSDp Checksum check failed for extended variant
+    RSDPExtendedChecksum,
+
+    /// Invalid signature for RSDT
+    InvalidRSDTSignature,
+
+    /// Invalid table-size for RSDT, has to be a multiple of 4
+    InvalidRSDTTableSize,
+
+    /// Invalid signature for XSDT
+    InvalidXSDTSignature,
+
+    /// Invalid table-size for XSDT, has to be a multiple of 8
+    InvalidXSDTTableSize,
+
+    /// The length of an SDT is smaller than the SDT header itself
+    InvalidSDTLength,
+
+    /// An entry in the MADT is shorter than its type requires, the entry is skipped
+    InvalidMADTEntrySize { entry_type: u8, entry_len: u8 },
+
+    /// The FADT is shorter than the acpi 1.0 version of it
+    InvalidFADTLength,
+
+    /// The `\_S5` package in the DSDT does not start with two integer constants
+    InvalidS5Package,
+
+    /// The HPET table is too short or does not describe memory mapped registers
+    InvalidHPETTable,
+
+    /// A remapping structure or device scope in the DMAR is truncated or malformed
+    InvalidDMARStructure,
+
+    /// A block or device entry in the IVRS is truncated or malformed
+    InvalidIVRSBlock,
+
+    /// The checksum calculation failed for some SDT entry
+    SDTChecksum,
+
+    /// The checksum of the table with `signature` located at `addr` does not add up
+    TableChecksum { signature: [u8; 4], addr: u64 },
+
+    /// Tried to read acpi tables from memory that is not accessible
+    PhysMem(physmem::Error),
+
+    /// Writing the table dump to its output failed
+    DumpWrite,
+}
+
+impl From<physmem::Error> for Error {
+    fn from(err: physmem::Error) -> Self {
+        Error::PhysMem(err)
+    }
+}
+
+impl From<core::fmt::Error> for Error {
+    fn from(_: core::fmt::Error) -> Self {
+        Error::DumpWrite
+    }
+}
+
+/// Root System Description Pointer
+/// This table is used to locate the XSDT
+/// https://wiki.osdev.org/RSDP
+#[derive(Default, Copy, Clone)]
+#[repr(C, packed)]
+pub struct Rsdp {
+    signature:         [u8; 8],
+    checksum:          u8,
+    oem_id:            [u8; 6],
+    revision:          u8,
+    rsdt_address:      u32,
+}
+
+/// Root System Description Pointer
+/// This table is used to locate the XSDT
+/// https://wiki.osdev.org/RSDP
+#[derive(Copy, Clone)]
+#[repr(C, packed)]
+pub struct RsdpExtended {
+    first_part:        Rsdp,
+    length:            u32,
+    xsdt_address:      u64,
+    extended_checksum: u8,
+    reserved:          [u8; 3],
+}
+
+unsafe impl Pod for Rsdp {}
+unsafe impl Pod for RsdpExtended {}
+
+/// Some rsdt configuration flags, these are necessary since options like the entry-size can change
+/// depending on the acpi version
+#[derive(Default)]
+struct RsdtConfig {
+    entry_size:  usize,
+    start_addr:  u64,
+    num_entries: usize,
+}
+
+/// System Descripter Table header
+/// All SDT's have this header + the actual sdt payload
+#[derive(Copy, Clone)]
+#[repr(C, packed)]
+pub struct SDTHeader {
+    signature:        [u8; 4],
+    length:           u32,
+    revision:         u8,
+    checksum:         u8,
+    oem_id:           [u8; 6],
+    oem_table_id:     [u8; 8],
+    oem_revision:     u32,
+    creator_id:       u32,
+    creator_revision: u32,
+}
+
+unsafe impl Pod for SDTHeader {}
+
+impl SDTHeader {
+    pub fn new<M: PhysMem>(mem: &M, addr: u64) -> Result<Self> {
+        let header = mem.read::<SDTHeader>(addr)?;
+
+        // Verify checksum over the entire SDT (Header + entry)
+        if checksum(mem, addr, header.length as usize)? != 0 {
+            return Err(Error::SDTChecksum);
+        }
+
+        Ok(header)
+    }
+}
+
+/// Sum up `len` bytes starting at `addr`. Valid acpi structures sum up to 0
+fn checksum<M: PhysMem>(mem: &M, addr: u64, len: usize) -> Result<u8> {
+    let mut buf = [0u8; 64];
+    let mut sum = 0u8;
+    for off in (0..len).step_by(buf.len()) {
+        let chunk = &mut buf[..core::cmp::min(64, len - off)];
+        mem.read_bytes(addr + off as u64, chunk)?;
+        sum = chunk.iter().fold(sum, |acc, &x| acc.wrapping_add(x));
+    }
+    Ok(sum)
+}
+
+/// Describe the table at `addr`. Tables with a bad checksum are described as well, it is up to the
+/// caller whether to use them
+fn table_info<M: PhysMem>(mem: &M, addr: u64) -> Result<TableInfo> {
+    let sdt_header = mem.read::<SDTHeader>(addr)?;
+    if (sdt_header.length as usize) < size_of::<SDTHeader>() {
+        return Err(Error::InvalidSDTLength);
+    }
+
+    Ok(TableInfo {
+        signature:      sdt_header.signature,
+        addr,
+        length:         sdt_header.length,
+        revision:       sdt_header.revision,
+        oem_id:         sdt_header.oem_id,
+        oem_table_id:   sdt_header.oem_table_id,
+        checksum_valid: checksum(mem, addr, sdt_header.length as usize)? == 0,
+    })
+}
+
+/// Call `f` with every range reserved by the DMAR or IVRS located at `addr`
+fn scan_table<M: PhysMem>(mem: &M, addr: u64, f: &mut impl FnMut(u64, u64)) -> Result<()> {
+    let table = table_info(mem, addr)?;
+    if !table.checksum_valid {
+        return Err(Error::TableChecksum { signature: table.signature, addr });
+    }
+
+    match &table.signature {
+        b"DMAR" => dmar::for_each_reserved(mem, &table, f),
+        _       => ivrs::for_each_reserved(mem, &table, f),
+    }
+}
+
+/// Read and validate the extended part of the rsdp located at `addr`. Later revisions may append
+/// fields, so the length field is used for the checksum
+fn rsdp_extended<M: PhysMem>(mem: &M, addr: u64) -> Result<RsdpExtended> {
+    let rsdp = mem.read::<RsdpExtended>(addr)?;
+    let length = rsdp.length as usize;
+    if length < size_of::<RsdpExtended>() || length > MAX_RSDP_LENGTH {
+        return Err(Error::InvalidRSDPLength);
+    }
+
+    if checksum(mem, addr, length)? != 0 {
+        return Err(Error::RSDPExtendedChecksum);
+    }
+    Ok(rsdp)
+}
+
+/// How broken tables are dealt with. The rsdp and the rsdt/xsdt are needed to find anything at
+/// all, every other table only enables some optional feature
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TablePolicy {
+    /// Fail parsing on the first broken structure
+    Strict,
+
+    /// Warn and skip broken tables, the features relying on them are disabled
+    Skip,
+
+    /// Warn about bad checksums but use the tables anyway, some firmware ships otherwise valid
+    /// tables with broken checksums
+    Ignore,
+}
+
+/// This struct contains various information we parsed out from the acpi table
+pub struct ParsedACPI {
+    /// Version of acpi running on this system
+    pub version: usize,
+
+    /// The parsed out rsdp or extended-rsdp
+    rsdp: Either<Rsdp, RsdpExtended>,
+
+    /// Some rsdt configuration options needed for further parsing
+    rsdt_config: RsdtConfig,
+
+    /// How broken tables are dealt with
+    policy: TablePolicy,
+
+    /// Physical address and length of the rsdp
+    rsdp_range: (u64, u64),
+
+    /// Physical address and length of the rsdt/xsdt
+    root_range: (u64, u64),
+
+    /// Every table referenced by the rsdt/xsdt
+    pub tables: TableRegistry,
+
+    /// Processors, interrupt controllers and interrupt routing described by the MADT
+    pub madt: Madt,
+
+    /// Power management registers described by the FADT
+    pub fadt: Option<Fadt>,
+
+    /// Location of the high precision event timer
+    pub hpet: Option<HpetInfo>,
+
+    /// Memory mapped pci configuration space regions, empty if only legacy io access is available
+    pub ecam: Vec<EcamRegion>,
+
+    /// Intel IOMMUs and their reserved memory regions
+    pub dmar: Option<Dmar>,
+
+    /// AMD IOMMUs and their reserved memory regions
+    pub ivrs: Option<Ivrs>,
+
+    /// Problems that were worked around while parsing, acpi features may be degraded because of
+    /// these
+    pub warnings: Vec<Error>,
+}
+
+impl Default for ParsedACPI {
+    /// Acpi without any tables, used when the firmware tables are unusable
+    fn default() -> Self {
+        Self {
+            version: 0,
+            rsdp: either::Left(Rsdp::default()),
+            rsdt_config: RsdtConfig::default(),
+            policy: config::ACPI_TABLE_POLICY,
+            rsdp_range: (0, 0),
+            root_range: (0, 0),
+            tables: TableRegistry::new(config::ACPI_TABLE_POLICY),
+            madt: Madt::default(),
+            fadt: None,
+            hpet: None,
+            ecam: Vec::new(),
+            dmar: None,
+            ivrs: None,
+            warnings: Vec::new(),
+        }
+    }
+}
+
+impl ParsedACPI {
+    /// Find and parse all the acpi information we will be making use of. All memory accesses go
+    /// through `mem`, so this can just as well run on the host against a dump of the tables. The
+    /// rsdp is searched for in the bios areas unless its address is already known
+    pub fn parse<M: PhysMem>(mem: &M, rsdp_addr: Option<u64>) -> Result<Self> {
+        Self::parse_with_policy(mem, rsdp_addr, config::ACPI_TABLE_POLICY)
+    }
+
+    /// Same as `parse`, but broken tables are dealt with according to `policy` instead of the one
+    /// from the boot config
+    pub fn parse_with_policy<M: PhysMem>(mem: &M, rsdp_addr: Option<u64>, policy: TablePolicy)
+            -> Result<Self> {
+        let mut acpi = Self { policy, tables: TableRegistry::new(policy), ..Self::default() };
+
+        // Parse out the rsdp
+        acpi.parse_rsdp(mem, rsdp_addr)?;
+
+        // Setup some configurations we need to parse out RSDT
+        acpi.rsdt_config(mem)?;
+
+        // Record every table listed in the rsdt/xsdt
+        for i in 0..acpi.rsdt_config.num_entries {
+            let table_ptr = acpi.table_ptr(mem, i)?;
+            if let Err(v) = acpi.parse_table(mem, table_ptr) {
+                acpi.tolerate(v)?;
+            }
+        }
+
+        // MADT found, parse out all active cores and interrupt controllers
+        if let Some(madt) = acpi.tables.find(b"APIC").copied() {
+            match Madt::parse(mem, &madt, &mut acpi.warnings) {
+                Ok(v) => acpi.madt = v,
+                Err(v) => acpi.tolerate(v)?,
+            }
+        }
+
+        // FADT found, it also points to the DSDT which is not listed in the rsdt/xsdt itself
+        if let Some(table) = acpi.tables.find(b"FACP").copied() {
+            match Fadt::parse(mem, &table) {
+                Ok(v) => acpi.fadt = Some(acpi.parse_dsdt(mem, v)?),
+                Err(v) => acpi.tolerate(v)?,
+            }
+        }
+
+        // HPET found, this is our reference clock
+        if let Some(table) = acpi.tables.find(b"HPET").copied() {
+            match HpetInfo::parse(mem, &table) {
+                Ok(v) => acpi.hpet = Some(v),
+                Err(v) => acpi.tolerate(v)?,
+            }
+        }
+
+        // MCFG found, pci configuration space can be accessed through memory
+        if let Some(table) = acpi.tables.find(b"MCFG").copied() {
+            match mcfg::parse(mem, &table) {
+                Ok(v) => acpi.ecam = v,
+                Err(v) => acpi.tolerate(v)?,
+            }
+        }
+
+        // IOMMUs, either from Intel or AMD
+        if let Some(table) = acpi.tables.find(b"DMAR").copied() {
+            match Dmar::parse(mem, &table) {
+                Ok(v) => acpi.dmar = Some(v),
+                Err(v) => acpi.tolerate(v)?,
+            }
+        }
+        if let Some(table) = acpi.tables.find(b"IVRS").copied() {
+            match Ivrs::parse(mem, &table) {
+                Ok(v) => acpi.ivrs = Some(v),
+                Err(v) => acpi.tolerate(v)?,
+            }
+        }
+        Ok(acpi)
+    }
+
+    /// Call `f` with every range `[start, end)` the IOMMU tables reserve for DMA. Unlike `parse`
+    /// this never touches the heap, so it can run before any memory is allocated. Tables that
+    /// can't be read through `mem` or are broken are skipped, returns how many were skipped
+    pub fn scan_dma_reserved<M: PhysMem>(mem: &M, rsdp_addr: Option<u64>,
+                                         mut f: impl FnMut(u64, u64)) -> Result<usize> {
+        let mut acpi = Self {
+            policy: TablePolicy::Strict,
+            tables: TableRegistry::new(TablePolicy::Strict),
+            ..Self::default()
+        };
+        acpi.parse_rsdp(mem, rsdp_addr)?;
+        acpi.rsdt_config(mem)?;
+
+        let mut skipped = 0;
+        for i in 0..acpi.rsdt_config.num_entries {
+            let addr = acpi.table_ptr(mem, i)?;
+            match mem.read::<[u8; 4]>(addr) {
+                Ok(signature) if &signature != b"DMAR" && &signature != b"IVRS" => continue,
+                Ok(_)  => {}
+                Err(_) => {
+                    skipped += 1;
+                    continue;
+                }
+            }
+            if scan_table(mem, addr, &mut f).is_err() {
+                skipped += 1;
+            }
+        }
+        Ok(skipped)
+    }
+
+    /// Physical address of the `idx`th table listed in the rsdt/xsdt
+    fn table_ptr<M: PhysMem>(&self, mem: &M, idx: usize) -> Result<u64> {
+        let addr = self.rsdt_config.start_addr + (idx * self.rsdt_config.entry_size) as u64;
+        match self.rsdt_config.entry_size {
+            4 => Ok(mem.read::<u32>(addr)? as u64),
+            8 => Ok(mem.read::<u64>(addr)?),
+            _ => unreachable!(),
+        }
+    }
+
+    /// Record the DSDT the `fadt` points to, and fill in the S5 sleep type from its AML
+    fn parse_dsdt<M: PhysMem>(&mut self, mem: &M, mut fadt: Fadt) -> Result<Fadt> {
+        if fadt.dsdt == 0 {
+            return Ok(fadt);
+        }
+
+        if let Err(v) = self.parse_table(mem, fadt.dsdt) {
+            self.tolerate(v)?;
+        }
+        if let Some(dsdt) = self.tables.find(b"DSDT").copied() {
+            match fadt::parse_s5(mem, &dsdt) {
+                Ok(v) => fadt.s5 = v,
+                Err(v) => self.tolerate(v)?,
+            }
+        }
+        Ok(fadt)
+    }
+
+    /// Physical address and length of every acpi structure we parsed (rsdp, rsdt/xsdt & tables)
+    pub fn ranges(&self) -> impl Iterator<Item = (u64, u64)> + '_ {
+        let roots = [self.rsdp_range, self.root_range].into_iter().filter(|r| r.1 != 0);
+        roots.chain(self.tables.iter().map(|t| (t.addr, t.length as u64)))
+    }
+
+    /// Physical ranges `[start, end)` that devices use for DMA on their own, as reported by the
+    /// IOMMU tables
+    pub fn dma_reserved(&self) -> impl Iterator<Item = (u64, u64)> + '_ {
+        let rmrrs = self.dmar.iter().flat_map(|d| d.reserved.iter().map(|r| (r.start, r.end)));
+        let ivmds = self.ivrs.iter().flat_map(|i| i.reserved.iter().map(|r| (r.start, r.end)));
+        rmrrs.chain(ivmds)
+    }
+
+    /// Record a problem with the firmware tables, unless strict parsing is configured in which
+    /// case it is returned as an error
+    fn tolerate(&mut self, err: Error) -> Result<()> {
+        if self.policy == TablePolicy::Strict {
+            return Err(err);
+        }
+        self.warnings.push(err);
+        Ok(())
+    }
+
+    /// Record the table at `addr` in the table registry. Tables with a bad checksum are still
+    /// recorded, but only handed out by the registry if the policy allows it
+    fn parse_table<M: PhysMem>(&mut self, mem: &M, addr: u64) -> Result<()> {
+        let table = table_info(mem, addr)?;
+        self.tables.add(table);
+
+        if !table.checksum_valid {
+            return Err(Error::TableChecksum { signature: table.signature, addr });
+        }
+        Ok(())
+    }
+
+    /// Parse out the rsdp. If `rsdp_addr` is provided, eg. by the boot config or a boot protocol
+    /// handoff, that address is used instead of scanning the bios areas
+    fn parse_rsdp<M: PhysMem>(&mut self, mem: &M, rsdp_addr: Option<u64>) -> Result<()> {
+        if let Some(addr) = rsdp_addr {
+            match self.rsdp_at(mem, addr) {
+                Ok(()) => return Ok(()),
+                Err(v) => self.tolerate(v)?,
+            }
+        }
+
+        // The bios data area holds the segment of the extended bios data area
+        let ebda: u64 = (mem.read::<u16>(0x40e)? as u64) << 4;
+
+        // The rsdp is located either in the first KiB of ebda or in the hardcoded address-range
+        let rsdp_possible_ranges = [
+            (ebda, ebda + 1024),
+            (0x000E0000, 0x000FFFFF),
+        ];
+
+        // Iterate through the possible locations of the rsdp until the magic string is found
+        // This SDP-entry can then be used to initialize the rsdp or rsdp_extended based on the acpi
+        // version
+        for range in rsdp_possible_ranges {
+            let start = range.0;
+            let end   = range.1;
+
+            // Find the RDSP entry, the signature may just be random data so keep looking if the
+            // structure turns out to be invalid
+            for addr in (start..end).step_by(0x10) {
+                // Only the signature is read here, the last slot of the range is less than a
+                // full rsdp away from 1MiB
+                if &mem.read::<[u8; 8]>(addr)? == b"RSD PTR " {
+                    match self.rsdp_at(mem, addr) {
+                        Ok(()) => return Ok(()),
+                        Err(v) => self.tolerate(v)?,
+                    }
+                }
+            }
+        }
+        Err(Error::RSDPSignatureNotFound)
+    }
+
+    /// Parse the rsdp located at `addr`
+    fn rsdp_at<M: PhysMem>(&mut self, mem: &M, addr: u64) -> Result<()> {
+        let local_rsdp = mem.read::<Rsdp>(addr)?;
+        if &local_rsdp.signature != b"RSD PTR " {
+            return Err(Error::RSDPSignatureNotFound);
+        }
+
+        // Verify checksum of the rsdp
+        if checksum(mem, addr, size_of::<Rsdp>())? != 0 {
+            return Err(Error::RSDPChecksum);
+        }
+
+        self.version = local_rsdp.revision as usize;
+
+        // Revision 0 means standard rsdp is used, revision 2 and above use the extended rsdp.
+        // There is no revision 1, but the standard part is valid so make use of it anyways
+        if self.version < 2 {
+            if self.version != 0 {
+                self.tolerate(Error::InvalidVersion)?;
+            }
+            self.rsdp = Either::Left(local_rsdp);
+            self.rsdp_range = (addr, size_of::<Rsdp>() as u64);
+            return Ok(());
+        }
+
+        // The first part is valid on its own, so fall back to the rsdt if the extended part is
+        // broken
+        match rsdp_extended(mem, addr) {
+            Ok(local_extended_rsdp) => {
+                self.rsdp = Either::Right(local_extended_rsdp);
+                self.rsdp_range = (addr, local_extended_rsdp.length as u64);
+            }
+            Err(v) => {
+                self.tolerate(v)?;
+                self.rsdp = Either::Left(local_rsdp);
+                self.rsdp_range = (addr, size_of::<Rsdp>() as u64);
+            }
+        }
+        Ok(())
+    }
+
+    /// Setup some rsdt configuration based on the version (rsdt vs xsdt). The xsdt is preferred,
+    /// but every table is listed in the rsdt as well, so it can be used if the xsdt is broken
+    fn rsdt_config<M: PhysMem>(&mut self, mem: &M) -> Result<()> {
+        let rsdp = match self.rsdp {
+            Either::Left(rsdp) => rsdp,
+            Either::Right(rsdp_extended) => {
+                match self.parse_root(mem, rsdp_extended.xsdt_address, 8) {
+                    Ok(()) => return Ok(()),
+                    Err(v) => self.tolerate(v)?,
+                }
+                rsdp_extended.first_part
+            }
+        };
+        self.parse_root(mem, rsdp.rsdt_address as u64, 4)
+    }
+
+    /// Parse the rsdt (`entry_size` 4) or xsdt (`entry_size` 8) located at `addr`
+    fn parse_root<M: PhysMem>(&mut self, mem: &M, addr: u64, entry_size: usize) -> Result<()> {
+        let (signature, invalid_signature, invalid_size) = match entry_size {
+            4 => (b"RSDT", Error::InvalidRSDTSignature, Error::InvalidRSDTTableSize),
+            8 => (b"XSDT", Error::InvalidXSDTSignature, Error::InvalidXSDTTableSize),
+            _ => unreachable!(),
+        };
+
+        let header = mem.read::<SDTHeader>(addr)?;
+        if (header.length as usize) < size_of::<SDTHeader>() {
+            return Err(Error::InvalidSDTLength);
+        }
+
+        if &header.signature != signature {
+            return Err(invalid_signature);
+        }
+
+        if !(header.length as usize - size_of::<SDTHeader>()).is_multiple_of(entry_size) {
+            return Err(invalid_size);
+        }
+
+        if checksum(mem, addr, header.length as usize)? != 0 {
+            if self.policy != TablePolicy::Ignore {
+                return Err(Error::SDTChecksum);
+            }
+            self.warnings.push(Error::SDTChecksum);
+        }
+
+        self.root_range = (addr, header.length as u64);
+        self.rsdt_config = RsdtConfig {
+            start_addr:  addr + size_of::<SDTHeader>() as u64,
+            num_entries: (header.length as usize - size_of::<SDTHeader>()) / entry_size,
+            entry_size,
+        };
+        Ok(())
+    }
+}
+
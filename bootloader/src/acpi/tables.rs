@@ -0,0 +1,99 @@
+//! Registry of all tables referenced by the rsdt/xsdt
+//!     - Every table is recorded while walking the rsdt/xsdt once, subsystems look up the tables
+//!       they need by signature afterwards
+//!     - Tables with a bad checksum are recorded too, but lookups only return them if the table
+//!       policy the tables are parsed with is `Ignore`
+
+use super::TablePolicy;
+
+use alloc::vec::Vec;
+
+/// Information about a single acpi table
+#[derive(Debug, Clone, Copy)]
+pub struct TableInfo {
+    /// 4-byte signature, eg. `APIC` for the MADT
+    pub signature:      [u8; 4],
+
+    /// Physical address of the table header
+    pub addr:           u64,
+
+    /// Length of the entire table, including the header
+    pub length:         u32,
+
+    /// Revision of the table structure
+    pub revision:       u8,
+
+    /// OEM that supplied the table
+    pub oem_id:         [u8; 6],
+
+    /// OEM identifier of this particular table
+    pub oem_table_id:   [u8; 8],
+
+    /// Whether all bytes of the table sum up to 0
+    pub checksum_valid: bool,
+}
+
+impl TableInfo {
+    /// Physical address of the table payload following the header
+    pub fn payload(&self) -> u64 {
+        self.addr + core::mem::size_of::<super::SDTHeader>() as u64
+    }
+
+    /// Physical address right past the end of the table
+    pub fn end(&self) -> u64 {
+        self.addr + self.length as u64
+    }
+
+    /// Whether subsystems should make use of this table when parsing with `policy`
+    pub fn usable(&self, policy: TablePolicy) -> bool {
+        self.checksum_valid || policy == TablePolicy::Ignore
+    }
+}
+
+/// All tables found in the rsdt/xsdt, in the order they are listed
+#[derive(Debug)]
+pub struct TableRegistry {
+    tables: Vec<TableInfo>,
+
+    /// Policy the tables are parsed with, decides which tables lookups return
+    policy: TablePolicy,
+}
+
+impl TableRegistry {
+    /// Registry without any tables, handing out tables according to `policy`
+    pub fn new(policy: TablePolicy) -> Self {
+        Self { tables: Vec::new(), policy }
+    }
+
+    /// Record a newly found table
+    pub(super) fn add(&mut self, table: TableInfo) {
+        self.tables.push(table);
+    }
+
+    /// First usable table with the given `signature`
+    pub fn find(&self, signature: &[u8; 4]) -> Option<&TableInfo> {
+        self.find_all(signature).next()
+    }
+
+    /// All usable tables with the given `signature`, some like the SSDT can show up multiple times
+    pub fn find_all(&self, signature: &[u8; 4]) -> impl Iterator<Item = &TableInfo> + '_ {
+        let signature = *signature;
+        let policy    = self.policy;
+        self.iter().filter(move |t| t.signature == signature && t.usable(policy))
+    }
+
+    /// Iterate over all tables, including unusable ones
+    pub fn iter(&self) -> impl Iterator<Item = &TableInfo> {
+        self.tables.iter()
+    }
+
+    /// Number of tables found
+    pub fn len(&self) -> usize {
+        self.tables.len()
+    }
+
+    /// Whether no tables were found at all
+    pub fn is_empty(&self) -> bool {
+        self.tables.is_empty()
+    }
+}
@@ -0,0 +1,416 @@
+//! Host tests of the acpi parser
+//!     - `firecracker` loads real tables from `testdata/`, one file per table named after its
+//!       signature and physical address
+//!     - `Tables` builds tables shaped like the ones QEMU's q35 machine provides, to cover the
+//!       features the firecracker tables don't use (reset register, `\_S5`, a rsdt next to the
+//!       xsdt)
+
+use super::*;
+use crate::physmem::SliceMem;
+
+/// Size of the memory the tables are loaded into, covers the bios areas the rsdp is searched in
+const MEM_SIZE: usize = 1024 * 1024;
+
+/// Load all tables in `testdata/<name>` into memory at the address their file name lists
+fn load(name: &str) -> SliceMem<Vec<u8>> {
+    let dir = format!("{}/src/acpi/testdata/{}", env!("CARGO_MANIFEST_DIR"), name);
+    let mut mem = SliceMem::new(0, vec![0u8; MEM_SIZE]);
+    for entry in std::fs::read_dir(&dir).unwrap() {
+        let path = entry.unwrap().path();
+        let stem = path.file_stem().unwrap().to_str().unwrap();
+        let (_, addr) = stem.split_once('_').unwrap();
+        let addr = u64::from_str_radix(addr, 16).unwrap();
+        mem.write_bytes(addr, &std::fs::read(&path).unwrap()).unwrap();
+    }
+    mem
+}
+
+/// Physical memory with acpi tables built in place
+struct Tables(SliceMem<Vec<u8>>);
+
+impl Tables {
+    fn new() -> Self {
+        Self(SliceMem::new(0, vec![0u8; MEM_SIZE]))
+    }
+
+    /// Write a table with `signature` and `payload` to `addr`, with the ids QEMU uses
+    fn sdt(&mut self, addr: u64, signature: &[u8; 4], revision: u8, payload: &[u8]) {
+        let mut table = Vec::new();
+        table.extend_from_slice(signature);
+        table.extend_from_slice(&((size_of::<SDTHeader>() + payload.len()) as u32).to_le_bytes());
+        table.extend_from_slice(&[revision, 0]);
+        table.extend_from_slice(b"BOCHS ");
+        table.extend_from_slice(b"BXPC");
+        table.extend_from_slice(signature);
+        table.extend_from_slice(&1u32.to_le_bytes());
+        table.extend_from_slice(b"BXPC");
+        table.extend_from_slice(&1u32.to_le_bytes());
+        table.extend_from_slice(payload);
+        table[9] = fix_checksum(&table);
+        self.0.write_bytes(addr, &table).unwrap();
+    }
+
+    /// Write a revision 2 rsdp pointing to both `rsdt` and `xsdt` to `addr`
+    fn rsdp(&mut self, addr: u64, rsdt: u32, xsdt: u64) {
+        let mut rsdp = Vec::new();
+        rsdp.extend_from_slice(b"RSD PTR \0BOCHS \x02");
+        rsdp.extend_from_slice(&rsdt.to_le_bytes());
+        rsdp[8] = fix_checksum(&rsdp);
+        rsdp.extend_from_slice(&36u32.to_le_bytes());
+        rsdp.extend_from_slice(&xsdt.to_le_bytes());
+        rsdp.extend_from_slice(&[0; 4]);
+        rsdp[32] = fix_checksum(&rsdp);
+        self.0.write_bytes(addr, &rsdp).unwrap();
+    }
+
+    /// Corrupt the checksum of the table at `addr`
+    fn break_checksum(&mut self, addr: u64) {
+        let checksum: u8 = self.0.read(addr + 9).unwrap();
+        self.0.write(addr + 9, checksum.wrapping_add(1)).unwrap();
+    }
+}
+
+/// Value for a checksum byte that is still 0 in `bytes`, so they sum up to 0
+fn fix_checksum(bytes: &[u8]) -> u8 {
+    bytes.iter().fold(0u8, |acc, &x| acc.wrapping_sub(x))
+}
+
+/// Generic address of an io port register
+fn gas_io(port: u64, bit_width: u8) -> [u8; 12] {
+    let mut gas = [0u8; 12];
+    gas[0] = gas::SYSTEM_IO;
+    gas[1] = bit_width;
+    gas[4..].copy_from_slice(&port.to_le_bytes());
+    gas
+}
+
+const RSDP: u64 = 0xf5b10;
+const RSDT: u64 = 0xe0000;
+const XSDT: u64 = 0xe0040;
+const FACP: u64 = 0xe0100;
+const DSDT: u64 = 0xe0200;
+const APIC: u64 = 0xe0400;
+
+/// The tables of a q35 machine with 4 cores
+fn qemu() -> Tables {
+    let mut tables = Tables::new();
+
+    // Local APIC address and PCAT_COMPAT, followed by the entries
+    let mut madt = Vec::new();
+    madt.extend_from_slice(&0xfee0_0000u32.to_le_bytes());
+    madt.extend_from_slice(&1u32.to_le_bytes());
+    for i in 0..4u8 {
+        madt.extend_from_slice(&[0, 8, i, i, 1, 0, 0, 0]);
+    }
+    madt.extend_from_slice(&[1, 12, 0, 0, 0x00, 0x00, 0xc0, 0xfe, 0, 0, 0, 0]);
+    madt.extend_from_slice(&[2, 10, 0, 0, 2, 0, 0, 0, 0, 0]);
+    for irq in [5, 9, 10, 11] {
+        madt.extend_from_slice(&[2, 10, 0, irq, irq, 0, 0, 0, 0xd, 0]);
+    }
+    madt.extend_from_slice(&[4, 6, 0xff, 0, 0, 1]);
+    tables.sdt(APIC, b"APIC", 1, &madt);
+
+    // Some AML in front of `Name(\_S5, Package(0x04) { 0x05, 0x05, Zero, Zero })`
+    let mut dsdt = vec![0x10, 0x06, b'\\', b'_', b'S', b'B', b'_'];
+    dsdt.extend_from_slice(&[0x08, b'\\', b'_', b'S', b'5', b'_', 0x12, 0x08, 0x04, 0x0a, 0x05,
+                             0x0a, 0x05, 0x00, 0x00]);
+    tables.sdt(DSDT, b"DSDT", 1, &dsdt);
+
+    // Revision 3 FADT, offsets are relative to the start of the payload
+    let mut fadt = vec![0u8; 244 - size_of::<SDTHeader>()];
+    let mut put = |off: usize, bytes: &[u8]| {
+        let off = off - size_of::<SDTHeader>();
+        fadt[off..off + bytes.len()].copy_from_slice(bytes);
+    };
+    put(40,  &(DSDT as u32).to_le_bytes());
+    put(48,  &0xb2u32.to_le_bytes());
+    put(52,  &[0xf1]);
+    put(64,  &0x604u32.to_le_bytes());
+    put(109, &2u16.to_le_bytes());
+    put(112, &0x84a5u32.to_le_bytes());
+    put(116, &gas_io(0xcf9, 8));
+    put(128, &[0x0f]);
+    put(140, &DSDT.to_le_bytes());
+    put(172, &gas_io(0x604, 16));
+    tables.sdt(FACP, b"FACP", 3, &fadt);
+
+    let rsdt: Vec<u8> = [FACP, APIC].iter().flat_map(|&a| (a as u32).to_le_bytes()).collect();
+    tables.sdt(RSDT, b"RSDT", 1, &rsdt);
+    let xsdt: Vec<u8> = [FACP, APIC].iter().flat_map(|&a| a.to_le_bytes()).collect();
+    tables.sdt(XSDT, b"XSDT", 1, &xsdt);
+    tables.rsdp(RSDP, RSDT as u32, XSDT);
+    tables
+}
+
+#[test]
+fn firecracker_madt() {
+    let acpi = ParsedACPI::parse(&load("firecracker"), None).unwrap();
+    assert_eq!(acpi.version, 2);
+    assert!(acpi.warnings.is_empty(), "{:?}", acpi.warnings);
+
+    let madt = &acpi.madt;
+    assert_eq!(madt.local_apic_addr, 0xfee0_0000);
+    assert_eq!(madt.processors.len(), 1);
+    assert_eq!((madt.processors[0].apic_id, madt.processors[0].uid), (0, 0));
+    assert!(madt.processors[0].enabled);
+    assert_eq!(madt.io_apics.len(), 1);
+    assert_eq!((madt.io_apics[0].id, madt.io_apics[0].addr), (0, 0xfec0_0000));
+}
+
+#[test]
+fn firecracker_fadt() {
+    let acpi = ParsedACPI::parse(&load("firecracker"), None).unwrap();
+
+    // Hardware reduced, so no reset register, no 8042 and no sleep states besides S0
+    let fadt = acpi.fadt.unwrap();
+    assert_eq!(fadt.dsdt, 0x9fd30);
+    assert!(fadt.reset_reg.is_none());
+    assert!(!fadt.has_8042);
+    assert_eq!(fadt.s5, None);
+
+    assert_eq!(acpi.ecam.len(), 1);
+    assert_eq!(acpi.ecam[0].base, 0xeec0_0000);
+    assert_eq!((acpi.ecam[0].start_bus, acpi.ecam[0].end_bus), (0, 0));
+}
+
+#[test]
+fn firecracker_ranges() {
+    let acpi = ParsedACPI::parse(&load("firecracker"), None).unwrap();
+    let ranges: Vec<(u64, u64)> = acpi.ranges().collect();
+    assert_eq!(ranges, [(0xe0000, 0x24), (0xa0e13, 0x3c), (0xa0c83, 0x114), (0xa0d97, 0x40),
+                        (0xa0dd7, 0x3c), (0x9fd30, 0xf53)]);
+    assert!(acpi.tables.find(b"HPET").is_none());
+    assert_eq!(acpi.dma_reserved().count(), 0);
+}
+
+#[test]
+fn mcfg_start_bus() {
+    const MCFG: u64 = 0xe0600;
+    let mut tables = qemu();
+
+    // Segment 0 with buses 0-63, and segment 1 starting at bus 0x80
+    let mut mcfg = vec![0u8; 8];
+    for (base, segment, start_bus, end_bus) in [(0xb000_0000u64, 0u16, 0u8, 0x3fu8),
+                                                (0xc000_0000, 1, 0x80, 0xff)] {
+        mcfg.extend_from_slice(&base.to_le_bytes());
+        mcfg.extend_from_slice(&segment.to_le_bytes());
+        mcfg.extend_from_slice(&[start_bus, end_bus, 0, 0, 0, 0]);
+    }
+    tables.sdt(MCFG, b"MCFG", 1, &mcfg);
+    let xsdt: Vec<u8> = [FACP, APIC, MCFG].iter().flat_map(|&a| a.to_le_bytes()).collect();
+    tables.sdt(XSDT, b"XSDT", 1, &xsdt);
+
+    let acpi = ParsedACPI::parse(&tables.0, None).unwrap();
+    assert_eq!(acpi.ecam.len(), 2);
+    assert_eq!(acpi.ecam[0].end(), 0xb400_0000);
+
+    // The base address is that of bus 0, which is not part of the region
+    let region = acpi.ecam[1];
+    assert_eq!((region.segment, region.start_bus, region.end_bus), (1, 0x80, 0xff));
+    assert_eq!(region.bus_addr(0x80), 0xc800_0000);
+    assert_eq!(region.end(), 0xd000_0000);
+}
+
+#[test]
+fn qemu_madt() {
+    let acpi = ParsedACPI::parse(&qemu().0, None).unwrap();
+    let madt = &acpi.madt;
+    assert!(madt.pcat_compat);
+    let apic_ids: Vec<u32> = madt.processors.iter().map(|p| p.apic_id).collect();
+    assert_eq!(apic_ids, [0, 1, 2, 3]);
+    assert_eq!(madt.overrides.len(), 5);
+    assert_eq!((madt.overrides[0].source, madt.overrides[0].gsi), (0, 2));
+    assert!(madt.overrides[1].flags.level_triggered());
+    assert_eq!(madt.local_nmis.len(), 1);
+    assert_eq!(madt.local_nmis[0].processor, madt::ALL_PROCESSORS);
+}
+
+#[test]
+fn qemu_fadt() {
+    let acpi = ParsedACPI::parse(&qemu().0, None).unwrap();
+    assert!(acpi.warnings.is_empty(), "{:?}", acpi.warnings);
+
+    let fadt = acpi.fadt.unwrap();
+    let reset_reg = fadt.reset_reg.unwrap();
+    assert_eq!(reset_reg.address_space, gas::SYSTEM_IO);
+    assert_eq!({ reset_reg.address }, 0xcf9);
+    assert_eq!(fadt.reset_value, 0x0f);
+    assert!(fadt.has_8042);
+    assert_eq!((fadt.smi_cmd, fadt.acpi_enable), (0xb2, 0xf1));
+    assert_eq!({ fadt.pm1a_cnt.address }, 0x604);
+    assert_eq!(fadt.s5, Some((5, 5)));
+}
+
+#[test]
+fn xsdt_fallback() {
+    let mut tables = qemu();
+    tables.break_checksum(XSDT);
+    let acpi = ParsedACPI::parse(&tables.0, None).unwrap();
+
+    // Everything is still found through the rsdt
+    assert_eq!(acpi.rsdt_config.entry_size, 4);
+    assert!(matches!(acpi.warnings[..], [Error::SDTChecksum]), "{:?}", acpi.warnings);
+    assert_eq!(acpi.madt.processors.len(), 4);
+    assert!(acpi.fadt.is_some());
+    assert!(acpi.ranges().any(|r| r == (RSDT, 44)));
+    assert!(!acpi.ranges().any(|r| r.0 == XSDT));
+}
+
+#[test]
+fn table_policy() {
+    let mut tables = qemu();
+    tables.break_checksum(APIC);
+
+    let acpi = ParsedACPI::parse_with_policy(&tables.0, None, TablePolicy::Skip).unwrap();
+    assert!(acpi.tables.find(b"APIC").is_none());
+    assert!(acpi.madt.processors.is_empty());
+    assert!(acpi.fadt.is_some());
+
+    let acpi = ParsedACPI::parse_with_policy(&tables.0, None, TablePolicy::Ignore).unwrap();
+    assert!(acpi.tables.find(b"APIC").is_some());
+    assert_eq!(acpi.madt.processors.len(), 4);
+    assert!(matches!(acpi.warnings[..], [Error::TableChecksum { addr: APIC, .. }]),
+            "{:?}", acpi.warnings);
+
+    assert!(matches!(ParsedACPI::parse_with_policy(&tables.0, None, TablePolicy::Strict),
+                     Err(Error::TableChecksum { addr: APIC, .. })));
+}
+
+#[test]
+fn rsdp_not_found() {
+    let mut tables = qemu();
+    tables.0.zero(RSDP, 36).unwrap();
+    assert!(matches!(ParsedACPI::parse(&tables.0, None), Err(Error::RSDPSignatureNotFound)));
+
+    // A configured address is used even outside of the bios areas
+    tables.rsdp(0x1000, RSDT as u32, XSDT);
+    assert_eq!(ParsedACPI::parse(&tables.0, Some(0x1000)).unwrap().madt.processors.len(), 4);
+}
+
+#[test]
+fn madt_short_entry() {
+    let mut tables = qemu();
+
+    // A local APIC entry that is 2 bytes short sits between two valid ones, followed by one that
+    // is longer than required
+    let mut madt = Vec::new();
+    madt.extend_from_slice(&0xfee0_0000u32.to_le_bytes());
+    madt.extend_from_slice(&0u32.to_le_bytes());
+    madt.extend_from_slice(&[0, 8, 0, 0, 1, 0, 0, 0]);
+    madt.extend_from_slice(&[0, 6, 1, 1, 1, 0]);
+    madt.extend_from_slice(&[0, 8, 2, 2, 1, 0, 0, 0]);
+    madt.extend_from_slice(&[0, 10, 3, 3, 1, 0, 0, 0, 0xff, 0xff]);
+    tables.sdt(APIC, b"APIC", 1, &madt);
+
+    let acpi = ParsedACPI::parse(&tables.0, None).unwrap();
+    let apic_ids: Vec<u32> = acpi.madt.processors.iter().map(|p| p.apic_id).collect();
+    assert_eq!(apic_ids, [0, 2, 3]);
+    assert!(matches!(acpi.warnings[..],
+                     [Error::InvalidMADTEntrySize { entry_type: 0, entry_len: 6 }]));
+}
+
+/// Info of the table `tables` has at `addr`
+fn table_info(tables: &Tables, addr: u64) -> TableInfo {
+    let header: SDTHeader = tables.0.read(addr).unwrap();
+    TableInfo {
+        signature:      header.signature,
+        addr,
+        length:         header.length,
+        revision:       header.revision,
+        oem_id:         header.oem_id,
+        oem_table_id:   header.oem_table_id,
+        checksum_valid: true,
+    }
+}
+
+#[test]
+fn s5_at_table_end() {
+    // The tables end right where memory ends, so any read past them fails
+    let mut tables = Tables::new();
+    let dsdt = |aml: &[u8]| (MEM_SIZE - size_of::<SDTHeader>() - aml.len()) as u64;
+
+    // Package whose last element ends exactly at the end of the table
+    let aml = [0x08, b'_', b'S', b'5', b'_', 0x12, 0x06, 0x02, 0x0a, 0x07, 0x0a, 0x05];
+    tables.sdt(dsdt(&aml), b"DSDT", 1, &aml);
+    let info = table_info(&tables, dsdt(&aml));
+    assert_eq!(fadt::parse_s5(&tables.0, &info).unwrap(), Some((7, 5)));
+
+    // The name takes up the last 4 bytes
+    let aml = [0x00, 0x00, 0x08, b'_', b'S', b'5', b'_'];
+    tables.sdt(dsdt(&aml), b"DSDT", 1, &aml);
+    let info = table_info(&tables, dsdt(&aml));
+    assert_eq!(fadt::parse_s5(&tables.0, &info).unwrap(), None);
+
+    // Not even room for a name
+    let aml = *b"_S5";
+    tables.sdt(dsdt(&aml), b"DSDT", 1, &aml);
+    let info = table_info(&tables, dsdt(&aml));
+    assert_eq!(fadt::parse_s5(&tables.0, &info).unwrap(), None);
+}
+
+#[test]
+fn s5_dword_prefix() {
+    // `Name(_S5, Package(0x04) { 0x00000005, 0x00000006, Zero, Zero })` with DWordPrefix constants
+    let mut tables = Tables::new();
+    let aml = [0x08, b'_', b'S', b'5', b'_', 0x12, 0x0e, 0x04, 0x0c, 0x05, 0x00, 0x00, 0x00,
+               0x0c, 0x06, 0x00, 0x00, 0x00, 0x00, 0x00];
+    tables.sdt(0xe0a00, b"DSDT", 1, &aml);
+    let info = table_info(&tables, 0xe0a00);
+    assert_eq!(fadt::parse_s5(&tables.0, &info).unwrap(), Some((5, 6)));
+
+    // A constant cut off by the end of the table is rejected
+    let aml = [0x08, b'_', b'S', b'5', b'_', 0x12, 0x08, 0x02, 0x0c, 0x05, 0x00, 0x00, 0x00,
+               0x0c, 0x06];
+    tables.sdt(0xe0b00, b"DSDT", 1, &aml);
+    let info = table_info(&tables, 0xe0b00);
+    assert!(matches!(fadt::parse_s5(&tables.0, &info), Err(Error::InvalidS5Package)));
+}
+
+#[test]
+fn dma_reserved_scan() {
+    const DMAR: u64 = 0xe0800;
+    const IVRS: u64 = 0xe0900;
+    let mut tables = qemu();
+
+    // DRHD covering all devices, followed by an RMRR for a usb controller at 00:14.0
+    let mut dmar = vec![38, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0];
+    dmar.extend_from_slice(&[0, 0, 16, 0, 1, 0, 0, 0]);
+    dmar.extend_from_slice(&0xfed9_0000u64.to_le_bytes());
+    dmar.extend_from_slice(&[1, 0, 32, 0, 0, 0, 0, 0]);
+    dmar.extend_from_slice(&0x7a00_0000u64.to_le_bytes());
+    dmar.extend_from_slice(&0x7a01_ffffu64.to_le_bytes());
+    dmar.extend_from_slice(&[1, 8, 0, 0, 0, 0, 0x14, 0]);
+    tables.sdt(DMAR, b"DMAR", 1, &dmar);
+
+    // A single IVMD that applies to all devices
+    let mut ivrs = vec![0u8; 12];
+    ivrs.extend_from_slice(&[0x20, 0, 32, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0]);
+    ivrs.extend_from_slice(&0x7b00_0000u64.to_le_bytes());
+    ivrs.extend_from_slice(&0x4000u64.to_le_bytes());
+    tables.sdt(IVRS, b"IVRS", 1, &ivrs);
+
+    // The last entry points past the end of memory, like a table that is not mapped yet
+    let xsdt: Vec<u8> = [FACP, APIC, DMAR, IVRS, 0x4000_0000].iter()
+        .flat_map(|&a| a.to_le_bytes()).collect();
+    tables.sdt(XSDT, b"XSDT", 1, &xsdt);
+
+    let mut scanned = Vec::new();
+    let skipped = ParsedACPI::scan_dma_reserved(&tables.0, None, |start, end| {
+        scanned.push((start, end))
+    }).unwrap();
+    assert_eq!(skipped, 1);
+    assert_eq!(scanned, [(0x7a00_0000, 0x7a02_0000), (0x7b00_0000, 0x7b00_4000)]);
+
+    let acpi = ParsedACPI::parse(&tables.0, None).unwrap();
+    assert_eq!(acpi.dma_reserved().collect::<Vec<_>>(), scanned);
+    assert_eq!(acpi.dmar.unwrap().reserved[0].scopes[0].path, [(0x14, 0)]);
+
+    // Broken tables are skipped without affecting the others
+    tables.break_checksum(DMAR);
+    let mut scanned = Vec::new();
+    let skipped = ParsedACPI::scan_dma_reserved(&tables.0, None, |start, end| {
+        scanned.push((start, end))
+    }).unwrap();
+    assert_eq!(skipped, 2);
+    assert_eq!(scanned, [(0x7b00_0000, 0x7b00_4000)]);
+}
@@ -2,20 +2,58 @@
 //!     - Manages IRQ lines (Can extend the traditional 16 that PIC handles to 24)
 //!     - Manages CPUs
 
-use crate::println;
+use crate::{
+    hpet::{Hpet, CALIBRATION_NS},
+};
+
+use core::ptr::{read_volatile, write_volatile};
 use x86::{
     cpuid::CpuId,
     msr, io,
+    apic::{
+        xapic::XAPIC, x2apic::X2APIC, ApicControl, ApicId, Icr, DeliveryMode, DestinationMode,
+        DeliveryStatus, DestinationShorthand, Level, TriggerMode,
+    },
 };
 
 /// Physical address we want the local APIC to be mapped at
 const APIC_BASE: u64 = 0xfee0_0000;
 
+/// Bits of `IA32_APIC_BASE` that globally enable the APIC, and switch it to x2APIC mode
+const APIC_BASE_ENABLE: u64 = 1 << 11;
+const APIC_BASE_X2APIC: u64 = 1 << 10;
+
+/// MSR of the first x2APIC register, the xAPIC register at offset `x` is at
+/// `X2APIC_MSR_BASE + x / 16`
+const X2APIC_MSR_BASE: u32 = 0x800;
+
+/// Spurious interrupt vector register, and the bit in it that software-enables the local APIC
+const SPURIOUS_VECTOR: u64 = 0x0f0;
+const APIC_ENABLE:     u32 = 1 << 8;
+
+/// Vector spurious interrupts are delivered to
+pub const SPURIOUS_INTERRUPT: u8 = 0xff;
+
+/// Local APIC timer registers
+const LVT_TIMER:     u64 = 0x320;
+const INITIAL_COUNT: u64 = 0x380;
+const CURRENT_COUNT: u64 = 0x390;
+const DIVIDE_CONFIG: u64 = 0x3e0;
+
+/// Mask off the interrupt of a local vector table entry
+const LVT_MASKED: u32 = 1 << 16;
+
+/// Divide configuration the timer is calibrated with, divides the input clock by 16
+pub const TIMER_DIVIDE_BY_16: u32 = 0b0011;
+
 pub type Result<T> = core::result::Result<T, Error>;
 #[derive(Debug)]
 pub enum Error {
     NoApicSupport,
     Nox2ApicSupport,
+
+    /// The APIC id does not fit into the 8-bit destination of an xAPIC IPI
+    InvalidApicId(u32),
 }
 
 pub struct Apic {
@@ -26,43 +64,45 @@ pub struct Apic {
 
 impl Apic {
     /// Initialize processors local APIC
+    ///
+    /// # Safety
+    /// Has to be called once on every core before using its local APIC. The APIC registers have
+    /// to be identity mapped
     pub unsafe fn init() -> Result<()> {
         let features = CpuId::new().get_feature_info().unwrap();
         if !features.has_apic() {
             return Err(Error::NoApicSupport);
         }
 
-        if !features.has_x2apic() {
-            println!("No x2APIC");
-            //return Err(Error::Nox2ApicSupport);
-        }
-
-        // Retrieve apic base
-        let apic_offset = get_apic_offset();
-
-        // Or in the APIC base that we want to use
-        let new_apic = APIC_BASE | apic_offset;
-
-        // Enable the xAPIC unconditionally
-        let new_apic = new_apic | (1 << 11);
-
         // Disable the PIC by masking off all interrupts
         io::outb(0xa1, 0xff);
         io::outb(0x21, 0xff);
 
-        // Reprogram APIC
-        msr::wrmsr(msr::IA32_APIC_BASE, new_apic);
+        // Firmware may have switched to x2APIC mode already, which can only be left by disabling
+        // the APIC entirely. Otherwise go through xAPIC mode, switching straight from disabled to
+        // x2APIC is not allowed
+        let current = msr::rdmsr(msr::IA32_APIC_BASE);
+        if current & APIC_BASE_X2APIC == 0 {
+            // Or in the APIC base that we want to use
+            let new_apic = APIC_BASE | get_apic_offset() | APIC_BASE_ENABLE;
+            msr::wrmsr(msr::IA32_APIC_BASE, new_apic);
 
-        // For xAPIC mode, virtually map in APIC physical memory
-        //if !features.has_x2apic() {
+            if features.has_x2apic() {
+                msr::wrmsr(msr::IA32_APIC_BASE, new_apic | APIC_BASE_X2APIC);
+            }
+        }
 
-        //}
+        // Software-enable the APIC so it accepts fixed interrupts such as IPIs
+        LocalApic::current().write(SPURIOUS_VECTOR, APIC_ENABLE | SPURIOUS_INTERRUPT as u32);
 
         Ok(())
     }
 }
 
 /// Get the physical base address of the APIC registers page
+///
+/// # Safety
+/// Reads `IA32_APIC_BASE`, the processor has to have a local APIC
 pub unsafe fn get_apic_base() -> u64 {
     let orig_ia32_apic_base = msr::rdmsr(msr::IA32_APIC_BASE);
     orig_ia32_apic_base & !0xfff
@@ -73,3 +113,156 @@ unsafe fn get_apic_offset() -> u64 {
     let orig_ia32_apic_base = msr::rdmsr(msr::IA32_APIC_BASE);
     orig_ia32_apic_base & 0xfff
 }
+
+/// Local APIC of the core this is running on, in whichever mode `Apic::init` put it
+pub enum LocalApic {
+    /// Registers are memory mapped, APIC ids are limited to 8 bits
+    XApic(XAPIC),
+
+    /// Registers are accessed through MSRs, APIC ids are 32 bits
+    X2Apic(X2APIC),
+}
+
+impl LocalApic {
+    /// Driver for the local APIC of this core
+    ///
+    /// # Safety
+    /// The local APIC has to be enabled, in xAPIC mode its registers have to be identity mapped
+    pub unsafe fn current() -> Self {
+        if msr::rdmsr(msr::IA32_APIC_BASE) & APIC_BASE_X2APIC != 0 {
+            return LocalApic::X2Apic(X2APIC::new());
+        }
+        let regs: &'static mut [u32] =
+            core::slice::from_raw_parts_mut(get_apic_base() as *mut _, 256);
+        LocalApic::XApic(XAPIC::new(regs))
+    }
+
+    /// Whether the APIC runs in x2APIC mode
+    pub fn is_x2apic(&self) -> bool {
+        matches!(self, LocalApic::X2Apic(_))
+    }
+
+    /// Read the register at `offset` in the xAPIC register page
+    ///
+    /// # Safety
+    /// Same as `current`, `offset` has to be a readable register
+    pub unsafe fn read(&self, offset: u64) -> u32 {
+        match self {
+            LocalApic::XApic(_)  => read_volatile((get_apic_base() + offset) as *const u32),
+            LocalApic::X2Apic(_) => msr::rdmsr(X2APIC_MSR_BASE + (offset as u32 >> 4)) as u32,
+        }
+    }
+
+    /// Write `value` to the register at `offset` in the xAPIC register page
+    ///
+    /// # Safety
+    /// Same as `current`, `offset` has to be a writable register
+    pub unsafe fn write(&mut self, offset: u64, value: u32) {
+        match self {
+            LocalApic::XApic(_)  => write_volatile((get_apic_base() + offset) as *mut u32, value),
+            LocalApic::X2Apic(_) => {
+                msr::wrmsr(X2APIC_MSR_BASE + (offset as u32 >> 4), value as u64)
+            }
+        }
+    }
+
+    /// Destination field for `apic_id`, which has to fit into 8 bits in xAPIC mode
+    fn destination(&self, apic_id: u32) -> Result<ApicId> {
+        match self {
+            LocalApic::XApic(_) => u8::try_from(apic_id)
+                .map(ApicId::XApic)
+                .map_err(|_| Error::InvalidApicId(apic_id)),
+            LocalApic::X2Apic(_) => Ok(ApicId::X2Apic(apic_id)),
+        }
+    }
+
+    /// Send an IPI with `vector` and `mode` to the core with `apic_id`
+    ///
+    /// # Safety
+    /// Same as `current`, the target has to be able to handle the interrupt
+    pub unsafe fn send(&mut self, apic_id: u32, vector: u8, mode: DeliveryMode) -> Result<()> {
+        let dest = self.destination(apic_id)?;
+        let (level, trigger) = match mode {
+            DeliveryMode::Init => (Level::Assert, TriggerMode::Level),
+            _                  => (Level::Assert, TriggerMode::Edge),
+        };
+        match self {
+            LocalApic::XApic(apic) => apic.send_ipi(Icr::for_xapic(vector, dest,
+                DestinationShorthand::NoShorthand, mode, DestinationMode::Physical,
+                DeliveryStatus::Idle, level, trigger)),
+            LocalApic::X2Apic(apic) => apic.send_ipi(Icr::for_x2apic(vector, dest,
+                DestinationShorthand::NoShorthand, mode, DestinationMode::Physical,
+                DeliveryStatus::Idle, level, trigger)),
+        }
+        Ok(())
+    }
+
+    /// Send an INIT IPI to the core with `apic_id`, resetting it
+    ///
+    /// # Safety
+    /// Same as `current`, whatever the target was running is lost
+    pub unsafe fn init_ipi(&mut self, apic_id: u32) -> Result<()> {
+        self.send(apic_id, 0, DeliveryMode::Init)
+    }
+
+    /// Send a startup IPI to the core with `apic_id`, it starts executing at `start_page * 4KiB`
+    ///
+    /// # Safety
+    /// Same as `current`, there has to be real mode code at `start_page`
+    pub unsafe fn startup_ipi(&mut self, apic_id: u32, start_page: u8) -> Result<()> {
+        self.send(apic_id, start_page, DeliveryMode::StartUp)
+    }
+
+    /// Signal the end of the interrupt currently being handled
+    pub fn eoi(&mut self) {
+        match self {
+            LocalApic::XApic(apic)  => apic.eoi(),
+            LocalApic::X2Apic(apic) => apic.eoi(),
+        }
+    }
+}
+
+/// Raise interrupt `vector` on the core with `apic_id`
+///
+/// # Safety
+/// Same as `LocalApic::current`, the target has to be able to handle the interrupt
+pub unsafe fn send_ipi(apic_id: u32, vector: u8) -> Result<()> {
+    LocalApic::current().send(apic_id, vector, DeliveryMode::Fixed)
+}
+
+/// Raise an NMI on the core with `apic_id`, this gets through even if it disabled interrupts
+///
+/// # Safety
+/// Same as `LocalApic::current`, the target has to have an NMI handler installed
+pub unsafe fn send_nmi(apic_id: u32) -> Result<()> {
+    LocalApic::current().send(apic_id, 0, DeliveryMode::NMI)
+}
+
+/// Signal the end of the interrupt currently being handled
+///
+/// # Safety
+/// Same as `LocalApic::current`
+pub unsafe fn eoi() {
+    LocalApic::current().eoi();
+}
+
+/// Measure the frequency of this core's local APIC timer in Hz, with its input clock divided by
+/// 16. The timer is left stopped
+///
+/// # Safety
+/// Same as `LocalApic::current`
+pub unsafe fn calibrate_timer(hpet: &Hpet) -> u64 {
+    let mut apic = LocalApic::current();
+
+    apic.write(LVT_TIMER, LVT_MASKED);
+    apic.write(DIVIDE_CONFIG, TIMER_DIVIDE_BY_16);
+
+    let start = hpet.counter();
+    apic.write(INITIAL_COUNT, u32::MAX);
+    hpet.sleep_ns(CALIBRATION_NS);
+    let remaining = apic.read(CURRENT_COUNT);
+    let elapsed   = hpet.ticks_to_ns(hpet.elapsed(start));
+    apic.write(INITIAL_COUNT, 0);
+
+    ((u32::MAX - remaining) as u128 * 1_000_000_000 / elapsed as u128) as u64
+}
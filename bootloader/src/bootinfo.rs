@@ -0,0 +1,65 @@
+//! Information the bootloader hands over to the kernel
+
+use crate::{
+    mm::MemLayout,
+    memmap::{Reservation, REGISTRY},
+};
+
+use core::sync::atomic::{AtomicPtr, AtomicUsize, Ordering};
+
+/// Boot information passed to the kernel on every core
+#[repr(C)]
+pub struct BootInfo {
+    /// Memory map retrieved from the BIOS by stage-1
+    pub mem_layout: MemLayout,
+
+    /// Physical ranges claimed during boot, sorted by address. Ranges whose owner is not
+    /// `reusable()` must not be handed out by the kernel
+    pub reservations: AtomicPtr<Reservation>,
+
+    /// Number of entries in `reservations`
+    pub num_reservations: AtomicUsize,
+
+    /// Physical address of the HPET registers, 0 if there is none
+    pub hpet_addr: u64,
+
+    /// Frequency of the TSC in Hz, 0 if it could not be calibrated
+    pub tsc_frequency: u64,
+
+    /// Frequency of the local APIC timers in Hz with the input clock divided by 16, 0 if they
+    /// could not be calibrated
+    pub apic_timer_frequency: u64,
+}
+
+impl BootInfo {
+    /// Boot information without any reservations, see `finalize`
+    pub fn new(mem_layout: MemLayout) -> Self {
+        Self {
+            mem_layout,
+            reservations:         AtomicPtr::new(core::ptr::null_mut()),
+            num_reservations:     AtomicUsize::new(0),
+            hpet_addr:            0,
+            tsc_frequency:        0,
+            apic_timer_frequency: 0,
+        }
+    }
+
+    /// Seal the memory registry and hand its entries to the kernel. Has to run after the last
+    /// allocation, any claim afterwards fails instead of moving the registry storage
+    pub fn finalize(&self) {
+        let mut registry = REGISTRY.lock();
+        registry.seal();
+        self.num_reservations.store(registry.entries().len(), Ordering::Release);
+        self.reservations.store(registry.entries().as_ptr() as *mut Reservation, Ordering::Release);
+    }
+
+    /// Physical ranges claimed during boot, empty until `finalize` ran
+    pub fn reservations(&self) -> &[Reservation] {
+        let reservations = self.reservations.load(Ordering::Acquire);
+        if reservations.is_null() {
+            return &[];
+        }
+        let len = self.num_reservations.load(Ordering::Acquire);
+        unsafe { core::slice::from_raw_parts(reservations, len) }
+    }
+}
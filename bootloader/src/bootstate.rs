@@ -0,0 +1,49 @@
+//! State shared by all cores while booting
+//!     - Built once by the BSP and read-only afterwards, except for the atomics tracking the AP
+//!       startup
+//!     - Handed to every core explicitly instead of being reached through globals
+
+use crate::{
+    acpi::ParsedACPI,
+    bootinfo::BootInfo,
+    paging::PageTable,
+    physmem::IdentityMem,
+    smp::{Bringup, CoreInfo},
+};
+
+use spin::Mutex;
+
+/// Everything the cores need to know about the system during boot
+pub struct BootState {
+    /// Parsed firmware tables
+    pub acpi:      ParsedACPI,
+
+    /// Parameters of every core, including the BSP
+    pub cores:     &'static [CoreInfo],
+
+    /// Information handed over to the kernel
+    pub boot_info: &'static BootInfo,
+
+    /// Progress of the AP startup
+    pub bringup:   Bringup,
+
+    /// Page tables all cores run with, changes to them are shot down on every core
+    pub page_table: Mutex<PageTable<IdentityMem>>,
+}
+
+impl BootState {
+    /// Boot state of `cores`, has to be created on the BSP
+    pub fn new(acpi: ParsedACPI, cores: &'static [CoreInfo], boot_info: &'static BootInfo,
+               page_table: PageTable<IdentityMem>) -> Self {
+        Self {
+            acpi, cores, boot_info,
+            bringup:    Bringup::new(cores),
+            page_table: Mutex::new(page_table),
+        }
+    }
+
+    /// The core with `apic_id`
+    pub fn core(&self, apic_id: u32) -> Option<&'static CoreInfo> {
+        self.cores.iter().find(|c| c.apic_id == apic_id)
+    }
+}
@@ -0,0 +1,45 @@
+//! Build-time configuration of the bootloader. Adjust these values and rebuild to change how the
+//! bootloader sets up the system
+
+use crate::acpi::TablePolicy;
+
+/// Size of the stack allocated for each core. Has to be a multiple of the page size. An additional
+/// unmapped guard page is placed below each stack
+pub const STACK_SIZE: u64 = 64 * 1024;
+
+/// Run memory self-tests over the memory pool of every core before it is started. Failing pages
+/// are claimed as bad memory and left out of the pool. This takes a while with large pools
+pub const MEMTEST: bool = false;
+
+/// Seed for the random pattern of the memory self-test
+pub const MEMTEST_SEED: u64 = 0x5eed_5eed_5eed_5eed;
+
+/// How firmware acpi tables that are broken are handled. Anything but `Strict` keeps booting with
+/// degraded acpi features
+pub const ACPI_TABLE_POLICY: TablePolicy = TablePolicy::Skip;
+
+/// Physical address of the acpi rsdp. Only needed on systems where it can't be found by scanning
+/// the bios areas
+pub const ACPI_RSDP: Option<u64> = None;
+
+/// APIC ids of the cores to start. By default all cores the firmware reports as enabled are
+/// started, listing cores here also allows starting online-capable ones
+pub const CORES: Option<&[u32]> = None;
+
+/// Upper bound on the number of cores to start, including the BSP. If the firmware reports more,
+/// only the first ones in MADT order are used
+pub const MAX_CORES: Option<usize> = None;
+
+/// Reset the system when the bootloader panics instead of halting, so unattended machines come
+/// back up on their own
+pub const REBOOT_ON_PANIC: bool = false;
+
+/// Physical memory set aside for each core, handed to it in its `smp::CoreInfo`. Has to be a
+/// multiple of the page size
+pub const CORE_MEMORY: u64 = 16 * 1024 * 1024;
+
+/// How long to wait for an application processor to check in after sending it the startup IPIs
+pub const AP_TIMEOUT_MS: u64 = 100;
+
+/// Stream all acpi tables over serial during boot, see `acpi::dump`
+pub const ACPI_DUMP: bool = false;
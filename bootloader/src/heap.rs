@@ -0,0 +1,167 @@
+//! Global heap allocator
+//!     - Linked-list allocator whose free list is kept sorted by address so neighbouring blocks
+//!       can be merged on free
+//!     - Backed by the frame allocator. When no free block is large enough, the heap grows by
+//!       pulling in more physical frames (identity mapped, so physical == virtual)
+
+use crate::{
+    mm::{self, PAGE_SIZE},
+    memmap::Owner,
+};
+
+use core::{
+    alloc::{GlobalAlloc, Layout},
+    mem::size_of,
+    ptr,
+};
+use spin::Mutex;
+
+/// Minimum amount of memory the heap requests from the frame allocator at once
+const GROW_SIZE: usize = 64 * 1024;
+
+/// Every allocation is rounded up to and aligned to this, so any leftover piece of a block is
+/// always large enough to hold a `FreeBlock` header
+const MIN_BLOCK: usize = size_of::<FreeBlock>();
+
+/// Header written at the start of every free block
+struct FreeBlock {
+    size: usize,
+    next: *mut FreeBlock,
+}
+
+/// Free list of the heap, sorted by address
+pub struct Heap {
+    head: *mut FreeBlock,
+}
+
+// The raw pointers in the free list are only ever accessed while holding the lock
+unsafe impl Send for Heap {}
+
+impl Heap {
+    /// Heap without any memory, it grows on the first allocation
+    pub const fn empty() -> Self {
+        Self { head: ptr::null_mut() }
+    }
+
+    /// Allocate a `size` byte block aligned to `align` from the first free block that fits
+    unsafe fn alloc_first_fit(&mut self, size: usize, align: usize) -> *mut u8 {
+        let mut prev: *mut *mut FreeBlock = &mut self.head;
+        while !(*prev).is_null() {
+            let block = *prev;
+            let start = block as usize;
+            let end   = start + (*block).size;
+
+            let alloc_start = align_up(start, align);
+            let alloc_end   = alloc_start + size;
+            if alloc_end <= end {
+                let next = (*block).next;
+
+                // Give back the unused tail of the block
+                let tail = if alloc_end < end {
+                    let tail = alloc_end as *mut FreeBlock;
+                    tail.write(FreeBlock { size: end - alloc_end, next });
+                    tail
+                } else {
+                    next
+                };
+
+                // Give back the unused head of the block that was skipped for alignment
+                if alloc_start > start {
+                    (*block).size = alloc_start - start;
+                    (*block).next = tail;
+                } else {
+                    *prev = tail;
+                }
+                return alloc_start as *mut u8;
+            }
+            prev = &mut (*block).next;
+        }
+        ptr::null_mut()
+    }
+
+    /// Return the block at `addr` to the free list, merging it with its neighbours
+    unsafe fn free(&mut self, addr: usize, size: usize) {
+        let mut prev: *mut FreeBlock = ptr::null_mut();
+        let mut next = self.head;
+        while !next.is_null() && (next as usize) < addr {
+            prev = next;
+            next = (*next).next;
+        }
+
+        let block = addr as *mut FreeBlock;
+        block.write(FreeBlock { size, next });
+
+        // Merge with the following block
+        if !next.is_null() && addr + size == next as usize {
+            (*block).size += (*next).size;
+            (*block).next = (*next).next;
+        }
+
+        // Merge with the preceding block
+        if prev.is_null() {
+            self.head = block;
+        } else if prev as usize + (*prev).size == addr {
+            (*prev).size += (*block).size;
+            (*prev).next = (*block).next;
+        } else {
+            (*prev).next = block;
+        }
+    }
+
+    /// Pull in enough frames from the frame allocator to satisfy an allocation of `size` bytes
+    unsafe fn grow(&mut self, size: usize) -> bool {
+        let size = align_up(core::cmp::max(size, GROW_SIZE), PAGE_SIZE as usize);
+        match mm::alloc_frames((size as u64) / PAGE_SIZE, Owner::Heap) {
+            Some(addr) => {
+                self.free(addr as usize, size);
+                true
+            }
+            None => false,
+        }
+    }
+}
+
+/// Heap wrapped in a lock so it can be used as the `#[global_allocator]`
+pub struct LockedHeap(Mutex<Heap>);
+
+impl LockedHeap {
+    /// Heap without any memory, it grows on the first allocation
+    pub const fn empty() -> Self {
+        Self(Mutex::new(Heap::empty()))
+    }
+}
+
+unsafe impl GlobalAlloc for LockedHeap {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let (size, align) = block_layout(layout);
+        let mut heap = self.0.lock();
+
+        let ptr = heap.alloc_first_fit(size, align);
+        if !ptr.is_null() {
+            return ptr;
+        }
+
+        // Worst case we need to skip `align` bytes of a fresh region to satisfy the alignment
+        if !heap.grow(size + align) {
+            return ptr::null_mut();
+        }
+        heap.alloc_first_fit(size, align)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        let (size, _) = block_layout(layout);
+        self.0.lock().free(ptr as usize, size);
+    }
+}
+
+/// Round the layout up so every block can later be reused as a `FreeBlock`
+fn block_layout(layout: Layout) -> (usize, usize) {
+    let size  = align_up(core::cmp::max(layout.size(), MIN_BLOCK), MIN_BLOCK);
+    let align = core::cmp::max(layout.align(), MIN_BLOCK);
+    (size, align)
+}
+
+/// Align `val` up to the next multiple of `align`, which must be a power of two
+fn align_up(val: usize, align: usize) -> usize {
+    (val + align - 1) & !(align - 1)
+}
@@ -0,0 +1,248 @@
+//! High Precision Event Timer
+//!     - Fixed frequency main counter, used as the reference clock to calibrate the TSC and the
+//!       local APIC timers
+//!     - Comparators can raise one-shot or periodic interrupts
+//!     - The main counter is either 64 or 32 bits wide, all counter arithmetic wraps at its width
+//!     - https://wiki.osdev.org/HPET
+
+use core::ptr::{read_volatile, write_volatile};
+use spin::Once;
+use x86::time::rdtsc;
+
+/// HPET of the system, set up once by the BSP and only read afterwards
+pub static HPET: Once<Hpet> = Once::new();
+
+/// Register offsets
+const CAPABILITIES:  u64 = 0x000;
+const CONFIGURATION: u64 = 0x010;
+const MAIN_COUNTER:  u64 = 0x0f0;
+
+/// Offsets of the per-timer registers, relative to the registers of the timer
+const TIMER_CONFIG:     u64 = 0x00;
+const TIMER_COMPARATOR: u64 = 0x08;
+
+/// Enable the main counter
+const ENABLE_CNF: u64 = 1 << 0;
+
+/// The main counter is 64 bits wide, otherwise only the low 32 bits count and wrap around
+const COUNT_SIZE_CAP: u64 = 1 << 13;
+
+/// Bits of a timer configuration register
+const TN_INT_ENB_CNF:  u64 = 1 << 2;
+const TN_TYPE_CNF:     u64 = 1 << 3;
+const TN_PER_INT_CAP:  u64 = 1 << 4;
+const TN_VAL_SET_CNF:  u64 = 1 << 6;
+const TN_INT_ROUTE:    u64 = 0x1f << 9;
+
+/// The specification caps the period of the main counter at 100ns
+const MAX_PERIOD_FS: u64 = 100_000_000;
+
+/// Femtoseconds per second
+const FS_PER_SEC: u64 = 1_000_000_000_000_000;
+
+/// Time calibrations are measured over
+pub const CALIBRATION_NS: u64 = 10_000_000;
+
+pub type Result<T> = core::result::Result<T, Error>;
+#[derive(Debug)]
+pub enum Error {
+    /// Period reported by the capabilities register is 0 or larger than allowed, this is not a
+    /// working HPET
+    InvalidPeriod(u64),
+
+    /// Timer does not exist on this HPET block
+    InvalidTimer(u8),
+
+    /// Timer does not support periodic mode
+    PeriodicNotSupported(u8),
+
+    /// Timer can't be routed to the requested I/O APIC input
+    InvalidRoute { timer: u8, route: u8 },
+}
+
+/// A single HPET block
+#[derive(Debug)]
+pub struct Hpet {
+    /// Physical address of the registers, they have to be identity mapped
+    base:         u64,
+
+    /// Femtoseconds per tick of the main counter
+    period_fs:    u64,
+
+    /// Number of comparators
+    num_timers:   u8,
+
+    /// Bits of the main counter that are implemented, differences between counter values have
+    /// to be masked with this to handle a 32-bit counter wrapping around
+    counter_mask: u64,
+}
+
+impl Hpet {
+    /// Initialize the HPET with its registers at `base` and start the main counter
+    ///
+    /// # Safety
+    /// `base` has to point to identity mapped HPET registers
+    pub unsafe fn init(base: u64) -> Result<Self> {
+        let caps = read_volatile((base + CAPABILITIES) as *const u64);
+        let period_fs = caps >> 32;
+        if period_fs == 0 || period_fs > MAX_PERIOD_FS {
+            return Err(Error::InvalidPeriod(period_fs));
+        }
+
+        let hpet = Self {
+            base,
+            period_fs,
+            num_timers:   ((caps >> 8) & 0x1f) as u8 + 1,
+            counter_mask: if (caps & COUNT_SIZE_CAP) != 0 { u64::MAX } else { u32::MAX as u64 },
+        };
+
+        // Disable all comparators that firmware might have left enabled, then start the counter
+        for timer in 0..hpet.num_timers {
+            hpet.disable(timer)?;
+        }
+        let config = hpet.read(CONFIGURATION);
+        hpet.write(CONFIGURATION, config | ENABLE_CNF);
+        Ok(hpet)
+    }
+
+    /// Current value of the main counter
+    pub fn counter(&self) -> u64 {
+        unsafe { self.read(MAIN_COUNTER) & self.counter_mask }
+    }
+
+    /// Ticks that passed since the main counter read `start`. A 32-bit counter wraps around
+    /// within minutes, so this is only valid for intervals shorter than that
+    pub fn elapsed(&self, start: u64) -> u64 {
+        self.counter().wrapping_sub(start) & self.counter_mask
+    }
+
+    /// Femtoseconds per tick of the main counter
+    pub fn period_fs(&self) -> u64 {
+        self.period_fs
+    }
+
+    /// Frequency of the main counter in Hz
+    pub fn frequency(&self) -> u64 {
+        FS_PER_SEC / self.period_fs
+    }
+
+    /// Number of comparators available
+    pub fn num_timers(&self) -> u8 {
+        self.num_timers
+    }
+
+    /// Convert nanoseconds to ticks of the main counter
+    pub fn ns_to_ticks(&self, ns: u64) -> u64 {
+        ((ns as u128 * 1_000_000) / self.period_fs as u128) as u64
+    }
+
+    /// Convert ticks of the main counter to nanoseconds
+    pub fn ticks_to_ns(&self, ticks: u64) -> u64 {
+        ((ticks as u128 * self.period_fs as u128) / 1_000_000) as u64
+    }
+
+    /// Busy wait for `ns` nanoseconds. The elapsed ticks are summed up between reads, so this
+    /// also works for waits longer than it takes a 32-bit counter to wrap around
+    pub fn sleep_ns(&self, ns: u64) {
+        let ticks = self.ns_to_ticks(ns);
+        let mut last    = self.counter();
+        let mut elapsed = 0;
+        while elapsed < ticks {
+            core::hint::spin_loop();
+            let now = self.counter();
+            elapsed += now.wrapping_sub(last) & self.counter_mask;
+            last = now;
+        }
+    }
+
+    /// Measure the TSC frequency in Hz
+    pub fn calibrate_tsc(&self) -> u64 {
+        let start     = self.counter();
+        let tsc_start = unsafe { rdtsc() };
+        self.sleep_ns(CALIBRATION_NS);
+        let tsc_end   = unsafe { rdtsc() };
+        let elapsed   = self.ticks_to_ns(self.elapsed(start));
+
+        ((tsc_end - tsc_start) as u128 * 1_000_000_000 / elapsed as u128) as u64
+    }
+
+    /// Raise an interrupt on I/O APIC input `route` once `ticks` ticks passed
+    ///
+    /// # Safety
+    /// The interrupt has to be handled by whoever receives it
+    pub unsafe fn oneshot(&self, timer: u8, route: u8, ticks: u64) -> Result<()> {
+        let config = self.timer_config(timer, route)? & !TN_TYPE_CNF;
+        self.write_timer(timer, TIMER_CONFIG, config);
+        self.write_timer(timer, TIMER_COMPARATOR, self.deadline(ticks));
+        self.write_timer(timer, TIMER_CONFIG, config | TN_INT_ENB_CNF);
+        Ok(())
+    }
+
+    /// Raise an interrupt on I/O APIC input `route` every `ticks` ticks
+    ///
+    /// # Safety
+    /// The interrupt has to be handled by whoever receives it
+    pub unsafe fn periodic(&self, timer: u8, route: u8, ticks: u64) -> Result<()> {
+        let config = self.timer_config(timer, route)?;
+        if (config & TN_PER_INT_CAP) == 0 {
+            return Err(Error::PeriodicNotSupported(timer));
+        }
+
+        // With `TN_VAL_SET_CNF` set, the first write sets the comparator and the second one the
+        // period it is incremented by after every interrupt
+        let config = config | TN_TYPE_CNF;
+        self.write_timer(timer, TIMER_CONFIG, config | TN_VAL_SET_CNF);
+        self.write_timer(timer, TIMER_COMPARATOR, self.deadline(ticks));
+        self.write_timer(timer, TIMER_COMPARATOR, ticks);
+        self.write_timer(timer, TIMER_CONFIG, config | TN_INT_ENB_CNF);
+        Ok(())
+    }
+
+    /// Stop `timer` from raising interrupts
+    ///
+    /// # Safety
+    /// The HPET registers have to be identity mapped
+    pub unsafe fn disable(&self, timer: u8) -> Result<()> {
+        if timer >= self.num_timers {
+            return Err(Error::InvalidTimer(timer));
+        }
+        let config = self.read_timer(timer, TIMER_CONFIG);
+        self.write_timer(timer, TIMER_CONFIG, config & !(TN_INT_ENB_CNF | TN_TYPE_CNF));
+        Ok(())
+    }
+
+    /// Value of the main counter `ticks` ticks from now, wrapped to the width of the counter
+    fn deadline(&self, ticks: u64) -> u64 {
+        self.counter().wrapping_add(ticks) & self.counter_mask
+    }
+
+    /// Configuration of `timer` with its interrupt routed to `route`, but not yet enabled
+    unsafe fn timer_config(&self, timer: u8, route: u8) -> Result<u64> {
+        if timer >= self.num_timers {
+            return Err(Error::InvalidTimer(timer));
+        }
+
+        // The upper half holds a bitmap of the I/O APIC inputs this timer can be routed to
+        let config = self.read_timer(timer, TIMER_CONFIG);
+        if route >= 32 || ((config >> 32) & (1 << route)) == 0 {
+            return Err(Error::InvalidRoute { timer, route });
+        }
+        Ok((config & !(TN_INT_ROUTE | TN_INT_ENB_CNF)) | ((route as u64) << 9))
+    }
+
+    unsafe fn read(&self, reg: u64) -> u64 {
+        read_volatile((self.base + reg) as *const u64)
+    }
+
+    unsafe fn write(&self, reg: u64, val: u64) {
+        write_volatile((self.base + reg) as *mut u64, val)
+    }
+
+    unsafe fn read_timer(&self, timer: u8, reg: u64) -> u64 {
+        self.read(0x100 + 0x20 * timer as u64 + reg)
+    }
+
+    unsafe fn write_timer(&self, timer: u8, reg: u64, val: u64) {
+        self.write(0x100 + 0x20 * timer as u64 + reg, val)
+    }
+}
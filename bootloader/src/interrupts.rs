@@ -0,0 +1,109 @@
+//! Interrupt handling
+//!     - A single IDT is shared by all cores, only the vectors the bootloader uses are present
+//!     - Any other interrupt or exception still ends in a triple fault, like it did before there
+//!       was an IDT
+
+use crate::{
+    apic::{self, SPURIOUS_INTERRUPT},
+    ipi, tlb,
+};
+
+use alloc::boxed::Box;
+use spin::Once;
+use x86::dtables::{self, DescriptorTablePointer};
+
+/// Vector of non-maskable interrupts
+pub const NMI_VECTOR: u8 = 2;
+
+/// Vector other cores raise to make a core pick up its command, see `ipi`
+pub const COMMAND_VECTOR: u8 = 0xf0;
+
+/// Vector raised to make a core invalidate its TLB, see `tlb`
+pub const SHOOTDOWN_VECTOR: u8 = 0xf1;
+
+/// Code segment selector of the 64-bit gdt set up by stage-1
+const KERNEL_CODE: u16 = 0x08;
+
+/// Present, ring 0, 64-bit interrupt gate
+const INTERRUPT_GATE: u8 = 0x8e;
+
+/// IDT of all cores, built by the first core that calls `init`
+static IDT: Once<Box<[Gate; 256]>> = Once::new();
+
+/// State the processor pushes when it takes an interrupt
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+pub struct InterruptStackFrame {
+    pub rip:    u64,
+    pub cs:     u64,
+    pub rflags: u64,
+    pub rsp:    u64,
+    pub ss:     u64,
+}
+
+/// Function an interrupt gate points to
+type Handler = extern "x86-interrupt" fn(InterruptStackFrame);
+
+/// Entry of the IDT
+#[repr(C)]
+#[derive(Debug, Default, Clone, Copy)]
+struct Gate {
+    offset_low:  u16,
+    selector:    u16,
+    ist:         u8,
+    flags:       u8,
+    offset_mid:  u16,
+    offset_high: u32,
+    reserved:    u32,
+}
+
+impl Gate {
+    /// Interrupt gate that calls `handler` with interrupts disabled
+    fn new(handler: Handler) -> Self {
+        let handler = handler as usize as u64;
+        Self {
+            offset_low:  handler as u16,
+            selector:    KERNEL_CODE,
+            ist:         0,
+            flags:       INTERRUPT_GATE,
+            offset_mid:  (handler >> 16) as u16,
+            offset_high: (handler >> 32) as u32,
+            reserved:    0,
+        }
+    }
+}
+
+/// Load the IDT on this core, building it first if no other core did so yet
+///
+/// # Safety
+/// The first call allocates on the heap, so it has to happen before `BootInfo::finalize`
+pub unsafe fn init() {
+    let idt = IDT.call_once(|| {
+        let mut idt = Box::new([Gate::default(); 256]);
+        idt[NMI_VECTOR as usize]         = Gate::new(nmi_handler);
+        idt[COMMAND_VECTOR as usize]     = Gate::new(command_handler);
+        idt[SHOOTDOWN_VECTOR as usize]   = Gate::new(shootdown_handler);
+        idt[SPURIOUS_INTERRUPT as usize] = Gate::new(spurious_handler);
+        idt
+    });
+    dtables::lidt(&DescriptorTablePointer::new_from_slice(&idt[..]));
+}
+
+extern "x86-interrupt" fn nmi_handler(_frame: InterruptStackFrame) {
+    ipi::nmi();
+}
+
+extern "x86-interrupt" fn command_handler(frame: InterruptStackFrame) {
+    unsafe {
+        ipi::handle(&frame);
+        apic::eoi();
+    }
+}
+
+extern "x86-interrupt" fn shootdown_handler(_frame: InterruptStackFrame) {
+    tlb::handle();
+    unsafe { apic::eoi(); }
+}
+
+/// Spurious interrupts must not be acknowledged
+extern "x86-interrupt" fn spurious_handler(_frame: InterruptStackFrame) {}
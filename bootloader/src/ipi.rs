@@ -0,0 +1,260 @@
+//! Remote commands between cores
+//!     - Every core has a command slot in its `CoreInfo`. The sender fills it in and raises
+//!       `COMMAND_VECTOR` on the target, which picks the command up in its interrupt handler
+//!     - A command that was not picked up yet is replaced by a newer one
+//!     - The handler never prints or takes locks, the interrupted code might hold them. It only
+//!       snapshots the state of the core into the `Report` in its `CoreInfo` and acknowledges the
+//!       request, the requesting core prints the snapshot
+//!     - A panicking core stops all other cores with an NMI, which gets through even if they run
+//!       with interrupts disabled
+
+use crate::{
+    println, apic, percpu,
+    interrupts::{InterruptStackFrame, COMMAND_VECTOR},
+    smp::{self, CoreInfo},
+};
+
+use core::sync::atomic::{AtomicBool, AtomicU32, AtomicU64, Ordering};
+
+/// Set by the first core that panics
+static PANICKING: AtomicBool = AtomicBool::new(false);
+
+/// Sequence number of the last request, every request gets its own so late answers to an
+/// earlier one are not mistaken for the current one
+static LAST_SEQ: AtomicU32 = AtomicU32::new(0);
+
+/// Time other cores get to stop after a panic
+const PANIC_HALT_NS: u64 = 10_000_000;
+
+/// Time a core gets to answer a request
+const REQUEST_TIMEOUT_NS: u64 = 10_000_000;
+
+pub type Result<T> = core::result::Result<T, Error>;
+#[derive(Debug)]
+pub enum Error {
+    /// Failed to send the IPI
+    Apic(apic::Error),
+
+    /// The core with this APIC id did not answer within `REQUEST_TIMEOUT_NS`
+    Timeout(u32),
+}
+
+/// Command for another core
+#[repr(u32)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Command {
+    /// Empty command slot
+    None,
+
+    /// Stop the core for good
+    Halt,
+
+    /// Report the counters of the core
+    ReportStats,
+
+    /// Report where the core was interrupted, then stop it for good
+    PanicDump,
+}
+
+impl Command {
+    fn from_raw(raw: u32) -> Self {
+        match raw {
+            1 => Command::Halt,
+            2 => Command::ReportStats,
+            3 => Command::PanicDump,
+            _ => Command::None,
+        }
+    }
+}
+
+/// State of a core at the time it picked up a request, written from its interrupt handler
+#[repr(C)]
+#[derive(Debug, Default)]
+pub struct Report {
+    /// Sequence number of the request the core should answer next
+    request:         AtomicU32,
+
+    /// Sequence number of the request the snapshot belongs to
+    done:            AtomicU32,
+
+    /// Where the core was interrupted
+    rip:             AtomicU64,
+    rsp:             AtomicU64,
+    rflags:          AtomicU64,
+
+    /// Counters of the core, see `percpu::Stats`
+    allocations:     AtomicU64,
+    allocated_bytes: AtomicU64,
+    remaining:       AtomicU64,
+    commands:        AtomicU64,
+}
+
+/// Copy of a `Report` taken by the requesting core
+#[derive(Debug, Clone, Copy)]
+pub struct Snapshot {
+    pub rip:             u64,
+    pub rsp:             u64,
+    pub rflags:          u64,
+    pub allocations:     u64,
+    pub allocated_bytes: u64,
+
+    /// Bytes left in the memory pool of the core
+    pub remaining:       u64,
+
+    /// Commands the core received, including this one
+    pub commands:        u64,
+}
+
+/// Send `command` to `core`
+///
+/// # Safety
+/// Same as `LocalApic::current`
+pub unsafe fn send(core: &CoreInfo, command: Command) -> apic::Result<()> {
+    core.command.store(command as u32, Ordering::Release);
+    apic::send_ipi(core.apic_id, COMMAND_VECTOR)
+}
+
+/// Send `command` to every online core in `cores` except for this one. Keeps going if a core
+/// can't be reached, returning the last error
+///
+/// # Safety
+/// Same as `send`
+pub unsafe fn broadcast(cores: &[CoreInfo], command: Command) -> apic::Result<()> {
+    let this = smp::current_apic_id();
+
+    let mut result = Ok(());
+    for core in cores.iter().filter(|c| c.is_online() && c.apic_id != this) {
+        if let Err(v) = send(core, command) {
+            result = Err(v);
+        }
+    }
+    result
+}
+
+/// Send `command` to `core` and wait for it to answer
+///
+/// # Safety
+/// Same as `send`
+pub unsafe fn request(core: &CoreInfo, command: Command) -> Result<Snapshot> {
+    let report = &core.report;
+    let seq = LAST_SEQ.fetch_add(1, Ordering::AcqRel).wrapping_add(1);
+    report.request.store(seq, Ordering::Release);
+    send(core, command).map_err(Error::Apic)?;
+
+    if !smp::wait_for(REQUEST_TIMEOUT_NS, || report.done.load(Ordering::Acquire) == seq) {
+        return Err(Error::Timeout(core.apic_id));
+    }
+    Ok(Snapshot {
+        rip:             report.rip.load(Ordering::Relaxed),
+        rsp:             report.rsp.load(Ordering::Relaxed),
+        rflags:          report.rflags.load(Ordering::Relaxed),
+        allocations:     report.allocations.load(Ordering::Relaxed),
+        allocated_bytes: report.allocated_bytes.load(Ordering::Relaxed),
+        remaining:       report.remaining.load(Ordering::Relaxed),
+        commands:        report.commands.load(Ordering::Relaxed),
+    })
+}
+
+/// Send `command` to every online core in `cores` except for this one, one after the other, and
+/// print their answers
+///
+/// # Safety
+/// Same as `send`
+pub unsafe fn report_all(cores: &[CoreInfo], command: Command) {
+    let this = smp::current_apic_id();
+    for core in cores.iter().filter(|c| c.is_online() && c.apic_id != this) {
+        let snapshot = match request(core, command) {
+            Ok(v) => v,
+            Err(v) => {
+                println!("Core {} did not answer: {:?}", core.index, v);
+                continue;
+            }
+        };
+
+        match command {
+            Command::None => {},
+            Command::Halt => println!("Core {} (APIC id {}) halted", core.index, core.apic_id),
+            Command::ReportStats => {
+                println!("Core {} (APIC id {}): {} allocations, {:#x} bytes, {:#x} bytes left, \
+                          {} commands", core.index, core.apic_id, snapshot.allocations,
+                         snapshot.allocated_bytes, snapshot.remaining, snapshot.commands);
+            }
+            Command::PanicDump => {
+                println!("Core {} (APIC id {}) stopped at rip {:#x} rsp {:#x} rflags {:#x}",
+                         core.index, core.apic_id, snapshot.rip, snapshot.rsp, snapshot.rflags);
+            }
+        }
+    }
+}
+
+/// Pick up and run the command of this core, called from the `COMMAND_VECTOR` handler
+pub(crate) fn handle(frame: &InterruptStackFrame) {
+    let Some(percpu) = percpu::current() else { return };
+    let core = percpu.core;
+
+    let command = Command::from_raw(core.command.swap(Command::None as u32, Ordering::AcqRel));
+    if command == Command::None {
+        return;
+    }
+    let stats = &percpu.stats;
+    stats.commands.add(1);
+
+    // Answer before halting, a halted core never gets to it
+    let report = &core.report;
+    report.rip.store(frame.rip, Ordering::Relaxed);
+    report.rsp.store(frame.rsp, Ordering::Relaxed);
+    report.rflags.store(frame.rflags, Ordering::Relaxed);
+    report.allocations.store(stats.allocations.get(), Ordering::Relaxed);
+    report.allocated_bytes.store(stats.allocated_bytes.get(), Ordering::Relaxed);
+    report.remaining.store(percpu.allocator.remaining(), Ordering::Relaxed);
+    report.commands.store(stats.commands.get(), Ordering::Relaxed);
+    report.done.store(report.request.load(Ordering::Acquire), Ordering::Release);
+
+    match command {
+        Command::Halt | Command::PanicDump => halt(Some(core)),
+        Command::None | Command::ReportStats => {},
+    }
+}
+
+/// Called from the NMI handler. Stops the core if another one panicked
+pub(crate) fn nmi() {
+    if PANICKING.load(Ordering::Acquire) {
+        halt(percpu::current().map(|p| p.core));
+    }
+}
+
+/// Stop all other online cores in `cores` before reporting a panic. Returns false if another
+/// core panicked first, the caller should halt then since it is about to be stopped anyways
+///
+/// # Safety
+/// Has to be called from the panic handler. Same as `send`
+pub unsafe fn stop_others(cores: &[CoreInfo]) -> bool {
+    if PANICKING.swap(true, Ordering::AcqRel) {
+        return false;
+    }
+
+    let this = smp::current_apic_id();
+    let others = || cores.iter().filter(move |c| c.is_online() && c.apic_id != this);
+    for core in others() {
+        let _ = apic::send_nmi(core.apic_id);
+    }
+    smp::wait_for(PANIC_HALT_NS, || others().next().is_none());
+    true
+}
+
+/// Stop this core for good, taking it offline so no more commands are sent to it
+pub fn halt(core: Option<&CoreInfo>) -> ! {
+    if let Some(core) = core {
+        core.online.store(false, Ordering::Release);
+    }
+    loop {
+        unsafe { core::arch::asm!("cli", "hlt"); }
+    }
+}
+
+/// Idle with interrupts enabled, only running the commands other cores send
+pub fn park() -> ! {
+    loop {
+        unsafe { core::arch::asm!("sti", "hlt"); }
+    }
+}
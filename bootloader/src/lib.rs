@@ -1,14 +1,26 @@
-#![no_std]
+#![cfg_attr(not(test), no_std)]
+#![feature(abi_x86_interrupt)]
+
+extern crate alloc;
 
 pub mod vga_buffer;
+pub mod serial;
 pub mod mm;
 pub mod acpi;
 pub mod apic;
-
-pub unsafe fn read_phys<T>(addr: u64) -> T {
-    core::ptr::read_volatile((addr) as *mut T)
-}
-
-pub unsafe fn write_phys<T>(addr: u64, val: T) {
-    core::ptr::write_volatile((addr) as *mut T, val);
-}
+pub mod config;
+pub mod paging;
+pub mod stack;
+pub mod heap;
+pub mod physmem;
+pub mod memmap;
+pub mod bootinfo;
+pub mod bootstate;
+pub mod memtest;
+pub mod hpet;
+pub mod pci;
+pub mod smp;
+pub mod percpu;
+pub mod interrupts;
+pub mod ipi;
+pub mod tlb;
@@ -2,28 +2,50 @@
 #![no_main]
 
 use bootloader::{
-    println, mm, apic,
-    acpi::{
-        self,
-        NUM_APICS,
-        CUR_APIC,
-    },
+    println, mm, apic, config, memtest,
+    mm::MemLayout,
+    memmap::{Owner, REGISTRY},
+    bootinfo::BootInfo,
+    bootstate::BootState,
+    paging::{self, PageTable},
+    physmem::IdentityMem,
+    heap::LockedHeap,
+    hpet::{Hpet, HPET},
+    pci::{Pci, PciConfig},
+    smp::{self, CoreInfo},
+    percpu, interrupts, ipi, tlb,
+    vga_buffer::WRITER,
+    serial::SERIAL,
+    acpi,
 };
 
 use core::panic::PanicInfo;
+use spin::Once;
+use alloc::{boxed::Box, vec::Vec};
 
-#[repr(packed, C)]
-pub struct MemLayout {
-    num_entries: u64,
-    mem_layout: [mm::E820Entry; 32],
-}
+extern crate alloc;
 
 //static mut V: [u8; 4096] = [0u8; 4096];
 
+#[global_allocator]
+/// Heap allocator backing `alloc`, grows on demand using the frame allocator
+static ALLOCATOR: LockedHeap = LockedHeap::empty();
+
+/// State shared by all cores, built by the BSP in `entry` and read-only afterwards
+static BOOT_STATE: Once<BootState> = Once::new();
+
 #[no_mangle]
 /// Entry-point of the stage2 bootloader
-pub extern "C" fn entry(arg1: &MemLayout) -> ! {
+pub extern "C" fn entry(mem_layout: u64) -> ! {
+    unsafe { percpu::clear(); }
     println!("Entered rust part of bootloader");
+
+    // Stage-1 only identity maps the first GiB of memory
+    let mem = unsafe { IdentityMem::new(0, paging::STAGE1_IDENTITY_MAP) };
+    let arg1 = match MemLayout::read(&mem, mem_layout) {
+        Ok(v) => v,
+        Err(v) => panic!("{:?}", v),
+    };
     assert!(arg1.num_entries < 32, "Too many memory regions found");
 
     //for i in 0..arg1.num_entries {
@@ -32,29 +54,182 @@ pub extern "C" fn entry(arg1: &MemLayout) -> ! {
     //             arg1.mem_layout[i].base + arg1.mem_layout[i].length, {arg1.mem_layout[i].typ}); 
     //}
 
-    // For some reason unwrapping here causes a segfault. Matching like this works though
-    let apic = unsafe { apic::Apic::init() };
-    let _apic = match apic {
+    // Initialize the frame allocator and replace the 1GiB identity map from stage-1 with our own
+    // page tables, so we have control over individual 4KiB pages
+    mm::FRAME_ALLOCATOR.lock().init(arg1.entries());
+
+    // Devices keep doing DMA to the ranges the IOMMU tables reserve, so they have to be out of the
+    // allocator before the first frame is handed out. Tables above the first GiB are only visible
+    // once our own page tables are in place, the page tables themselves come from low memory
+    exclude_dma_reserved(&mem);
+
+    let page_table = match PageTable::identity(mem) {
         Ok(v) => v,
         Err(v) => panic!("{:?}", v),
     };
-    
+    unsafe { page_table.switch_to(); }
+
+    // All of physical memory is identity mapped from here on
+    let mem = unsafe { IdentityMem::new(0, paging::identity_map_size()) };
+    let mut page_table = page_table.with_mem(mem);
+    exclude_dma_reserved(&mem);
+
+    // Record the memory the bootloader stages occupy
+    if let Err(v) = REGISTRY.lock().reserve_bootloader() {
+        panic!("{:?}", v);
+    }
+
     // For some reason unwrapping here causes a segfault. Matching like this works though
-    let acpi = unsafe { acpi::ParsedACPI::parse() };
-    let mut acpi = match acpi {
+    let apic = unsafe { apic::Apic::init() };
+    match apic {
+        Ok(()) => {}
+        Err(v) => panic!("{:?}", v),
+    }
+    unsafe { interrupts::init(); }
+    
+    // Broken firmware tables only disable the features that depend on them, without any tables at
+    // all we continue with just the BSP
+    let mut acpi = match acpi::ParsedACPI::parse(&mem, config::ACPI_RSDP) {
+        Ok(v) => v,
+        Err(v) => {
+            println!("Failed to parse acpi tables, continuing without them: {:?}", v);
+            acpi::ParsedACPI::default()
+        }
+    };
+    for warning in &acpi.warnings {
+        println!("Acpi warning: {:?}", warning);
+    }
+    if config::ACPI_DUMP {
+        println!("Dumping {} acpi tables over serial", acpi.ranges().count());
+        if let Err(v) = acpi::dump::dump(&mem, &acpi, &mut *SERIAL.lock()) {
+            println!("Failed to dump acpi tables: {:?}", v);
+        }
+    }
+    if let Some(fadt) = acpi.fadt {
+        fadt.install();
+    }
+
+    // Bring up the HPET as reference clock, and calibrate the TSC and local APIC timer against it
+    let mut timing = (0, 0);
+    if let Some(info) = acpi.hpet {
+        match unsafe { Hpet::init(info.addr) } {
+            Ok(v) => {
+                let hpet = HPET.call_once(|| v);
+                timing = (hpet.calibrate_tsc(), unsafe { apic::calibrate_timer(hpet) });
+                println!("TSC runs at {} Hz, APIC timer at {} Hz", timing.0, timing.1);
+            }
+            Err(v) => println!("Failed to initialize the hpet: {:?}", v),
+        }
+    }
+
+    let pci = unsafe { Pci::new(&acpi.ecam) };
+    let mut num_functions = 0;
+    pci.for_each_function(|_| num_functions += 1);
+    println!("Found {} pci functions", num_functions);
+
+    // Firmware sometimes shares memory between tables, so overlapping claims are not fatal here
+    {
+        let mut registry = REGISTRY.lock();
+        for (start, len) in acpi.ranges() {
+            if let Err(v) = registry.reserve(Owner::Acpi, start, len) {
+                println!("Failed to reserve acpi table: {:?}", v);
+            }
+        }
+    }
+
+    // Ranges the early scans missed are excluded now. Anything that was allocated from them in the
+    // meantime can't be taken back, devices might already have overwritten it
+    let dma_reserved: Vec<(u64, u64)> = acpi.dma_reserved().collect();
+    for &(start, end) in &dma_reserved {
+        mm::FRAME_ALLOCATOR.lock().exclude(start, end);
+    }
+    for &(start, end) in &dma_reserved {
+        let mut registry = REGISTRY.lock();
+        for r in registry.entries().iter().filter(|r| r.start < end && start < r.end) {
+            if r.owner.allocated() {
+                println!("Dma reserved range [{:#x}, {:#x}) was allocated as {}, devices may \
+                          overwrite it", start, end, r.owner.name());
+            }
+        }
+        if let Err(v) = registry.reserve_unclaimed(Owner::DmaReserved, start, end) {
+            println!("Failed to reserve dma region: {:?}", v);
+        }
+    }
+
+    //unsafe { println!("Done parsing acpi({}), found {} cores", acpi.version, acpi.madt.processors.len()); }
+
+    let apic_id = smp::current_apic_id();
+    acpi.madt.set_bsp(apic_id);
+
+    // Allocate a stack and memory pool for every core we are going to start. The BSP always needs
+    // them, even if acpi did not report any cores
+    let mut apic_ids: Vec<u32> = acpi.madt.boot_processors().map(|p| p.apic_id).collect();
+    if apic_ids.is_empty() {
+        apic_ids.push(apic_id);
+    }
+    let mut cores = match unsafe { smp::alloc_cores(&apic_ids, &mut page_table) } {
         Ok(v) => v,
         Err(v) => panic!("{:?}", v),
     };
 
-    //unsafe { println!("Done parsing acpi({}), found {} cores", acpi.version, NUM_APICS); }
-    //unsafe { println!("{}", CUR_APIC); }
+    // Weed out flaky memory before any of it is handed out for fuzzing
+    if config::MEMTEST {
+        for core in cores.iter_mut() {
+            unsafe { memtest::test_core(core, config::MEMTEST_SEED); }
+        }
+    }
+    let cores = cores.leak();
+
+    // The memory map is only filled in once the APs are up, see `bsp_entry`
+    let boot_info = Box::leak(Box::new(BootInfo::new(arg1)));
+    boot_info.hpet_addr            = acpi.hpet.map_or(0, |info| info.addr);
+    boot_info.tsc_frequency        = timing.0;
+    boot_info.apic_timer_frequency = timing.1;
+
+    // Move the BSP off of the stage-0/1 stack at 0x7c00 onto its own stack
+    let bsp = cores.iter().position(|c| c.apic_id == apic_id).unwrap_or(0);
+    let state = BOOT_STATE.call_once(|| BootState::new(acpi, cores, boot_info, page_table));
+    unsafe { cores[bsp].stack.switch_to(bsp_entry, state); }
+}
+
+/// Exclude the ranges the IOMMU tables reserve for DMA from the frame allocator, as far as the
+/// tables can be read through `mem`
+fn exclude_dma_reserved(mem: &IdentityMem) {
+    let scan = acpi::ParsedACPI::scan_dma_reserved(mem, config::ACPI_RSDP, |start, end| {
+        mm::FRAME_ALLOCATOR.lock().exclude(start, end);
+    });
+    match scan {
+        Ok(0) => {}
+        Ok(v) => println!("Skipped {} acpi tables while scanning for dma reserved memory", v),
+        Err(v) => println!("Failed to scan for dma reserved memory: {:?}", v),
+    }
+}
+
+/// Continuation of `entry` for the BSP once it is running on its own stack
+extern "C" fn bsp_entry(state: &'static BootState) -> ! {
+    let cores = state.cores;
+    let apic_id = smp::current_apic_id();
+    if let Some(core) = state.core(apic_id) {
+        unsafe { percpu::init(core); }
+    }
+
+    tlb::init(cores);
+    match unsafe { smp::start_aps(state, ap_entry) } {
+        Ok(v) => println!("{} of {} application processors came online", v, cores.len() - 1),
+        Err(v) => println!("Failed to start application processors: {:?}", v),
+    }
+    for core in cores.iter().filter(|c| !c.is_online()) {
+        println!("Core with APIC id {} never came online", core.apic_id);
+    }
 
-    let _ = unsafe { acpi.launch_next_ap() };
+    // All memory the bootloader needs is claimed at this point
+    state.boot_info.finalize();
+    REGISTRY.lock().print(state.boot_info.mem_layout.entries());
 
 
     // If this is the first core booting up
     //if ApicControl::bsp() {
-    //for i in 0..NUM_APICS {
+    //for i in 0..acpi.madt.processors.len() {
     //    // Initialize memory
     //        // Load memory map
 
@@ -64,28 +239,58 @@ pub extern "C" fn entry(arg1: &MemLayout) -> ! {
 
     //    // Load the kernel
 
-    //    // for each core {
-    //        // allocate stack
-    //        // 
     //}
 
-    // launch kernel[core_id]
+    // launch kernel[core_id] with `boot_info`
 
     println!("Done with stage2");
 
-    hlt_loop();
+    ipi::park();
+}
+
+#[no_mangle]
+/// Entry-point of the application processors, called by stage-1 once they are in long mode and on
+/// their own stack
+pub extern "C" fn ap_entry(core: &'static CoreInfo, state: &'static BootState) -> ! {
+    unsafe { percpu::clear(); }
+    if let Err(v) = unsafe { apic::Apic::init() } {
+        panic!("{:?}", v);
+    }
+
+    // The IDT has to be in place before the core is marked online, since that is when other cores
+    // start sending it IPIs
+    unsafe {
+        interrupts::init();
+        percpu::init(core);
+        smp::ap_online(&state.bringup, core);
+    }
+
+    ipi::park();
 }
 
 /// Executes hlt instruction in a loop which stops the cpu until a new interrupt
 /// is received
 pub fn hlt_loop() -> ! {
-    loop {}
+    loop {
+        unsafe { x86::halt(); }
+    }
 }
 
 #[panic_handler]
 /// Panic handler
 fn panic(info: &PanicInfo) -> ! {
+    // Stop all other cores first, so they don't keep running on shared state that might be broken
+    let cores = BOOT_STATE.r#try().map_or(&[][..], |state| state.cores);
+    if !unsafe { ipi::stop_others(cores) } {
+        ipi::halt(percpu::current().map(|p| p.core));
+    }
+
+    // One of the stopped cores might have been printing
+    unsafe { WRITER.force_unlock(); }
     println!("{}", *info);
+    if config::REBOOT_ON_PANIC {
+        acpi::reboot();
+    }
     hlt_loop();
 }
 
@@ -0,0 +1,332 @@
+//! Registry of physical memory claimed during boot
+//!     - Every subsystem that takes ownership of a physical range records it here under its name,
+//!       overlapping claims are rejected
+//!     - Storage comes straight from the frame allocator instead of the heap, since the heap
+//!       itself records its memory here when it grows
+//!     - The final list is handed to the kernel so it knows which ranges are in use. The registry
+//!       is sealed at that point, so its storage can't move anymore
+
+use crate::{
+    println,
+    mm::{E820Entry, PAGE_SIZE, FRAME_ALLOCATOR},
+};
+
+use core::mem::size_of;
+use spin::Mutex;
+
+/// Global registry of claimed physical memory
+pub static REGISTRY: Mutex<Registry> = Mutex::new(Registry::empty());
+
+/// Ranges the bootloader occupies before stage-2 starts running, see the memory layout in the
+/// README
+const BOOTLOADER_RANGES: [(Owner, u64, u64); 7] = [
+    (Owner::BootStack,         0x500,   0x7c00),
+    (Owner::Stage0,            0x7c00,  0x7e00),
+    (Owner::ApStartup,         0x7e00,  0x8000),
+    (Owner::Stage1,            0x8000,  0x8a00),
+    (Owner::Stage2,            0x10000, 0x40000),
+    (Owner::Stage2Image,       0x40000, 0x70000),
+    (Owner::InitialPageTables, 0x80000, 0x90000),
+];
+
+pub type Result<T> = core::result::Result<T, Error>;
+#[derive(Debug)]
+pub enum Error {
+    /// The range claimed in `new` overlaps with the already existing claim `existing`
+    Overlap { new: Reservation, existing: Reservation },
+
+    /// Frame allocator ran out of memory while growing the registry
+    OutOfMemory,
+
+    /// Tried to claim memory after the registry was handed to the kernel
+    Sealed,
+}
+
+/// Subsystem that owns a reserved range
+#[repr(u32)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Owner {
+    /// Real mode stack stage-0 and stage-1 set up below 0x7c00, the BSP runs on it until it
+    /// switches to its own stack
+    BootStack,
+
+    /// Stage-0 bootloader (boot sector)
+    Stage0,
+
+    /// Data shared with application processors while they start up
+    ApStartup,
+
+    /// Stage-1 bootloader, also the AP entry point
+    Stage1,
+
+    /// Stage-2 bootloader once relocated to its final address
+    Stage2,
+
+    /// Raw stage-2 image stage-1 loads from disk before copying its sections into place
+    Stage2Image,
+
+    /// Page tables set up by stage-1
+    InitialPageTables,
+
+    /// Page tables set up by stage-2
+    PageTables,
+
+    /// Per-core stacks, including their guard pages
+    Stacks,
+
+    /// Memory backing the global heap
+    Heap,
+
+    /// Storage of this registry
+    Registry,
+
+    /// ACPI tables provided by the firmware
+    Acpi,
+
+    /// Memory pools of the individual cores
+    CoreMemory,
+
+    /// Memory that failed the memory self-test
+    BadMemory,
+
+    /// Memory devices use for DMA on their own, reported by the IOMMU tables
+    DmaReserved,
+}
+
+impl Owner {
+    /// Human readable name of the owner
+    pub fn name(&self) -> &'static str {
+        match self {
+            Owner::BootStack         => "boot stack",
+            Owner::Stage0            => "stage-0",
+            Owner::ApStartup         => "ap startup",
+            Owner::Stage1            => "stage-1",
+            Owner::Stage2            => "stage-2",
+            Owner::Stage2Image       => "stage-2 image",
+            Owner::InitialPageTables => "initial page tables",
+            Owner::PageTables        => "page tables",
+            Owner::Stacks            => "stacks",
+            Owner::Heap              => "heap",
+            Owner::Registry          => "memory registry",
+            Owner::Acpi              => "acpi tables",
+            Owner::CoreMemory        => "core memory",
+            Owner::BadMemory         => "bad memory",
+            Owner::DmaReserved       => "dma reserved",
+        }
+    }
+
+    /// Whether the kernel can reuse this range once it took over from the bootloader
+    pub fn reusable(&self) -> bool {
+        matches!(self,
+                 Owner::BootStack | Owner::Stage0 | Owner::Stage2 | Owner::Stage2Image |
+                 Owner::InitialPageTables)
+    }
+
+    /// Whether this range was handed out by the frame allocator
+    pub fn allocated(&self) -> bool {
+        matches!(self,
+                 Owner::PageTables | Owner::Stacks | Owner::Heap | Owner::Registry |
+                 Owner::CoreMemory)
+    }
+}
+
+/// A claimed physical range `[start, end)`
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+pub struct Reservation {
+    pub start: u64,
+    pub end:   u64,
+    pub owner: Owner,
+}
+
+/// Claimed ranges, sorted by address. Adjacent claims of the same owner are merged
+pub struct Registry {
+    entries: *mut Reservation,
+    len:     usize,
+    cap:     usize,
+
+    /// Set once the entries were handed to the kernel
+    sealed:  bool,
+}
+
+// The storage is only ever accessed while holding the lock
+unsafe impl Send for Registry {}
+
+impl Registry {
+    /// Registry without any storage, it grows on the first reservation
+    pub const fn empty() -> Self {
+        Self { entries: core::ptr::null_mut(), len: 0, cap: 0, sealed: false }
+    }
+
+    /// Reject all further claims, the entries stay where they are from now on
+    pub fn seal(&mut self) {
+        self.sealed = true;
+    }
+
+    /// All claimed ranges, sorted by address
+    pub fn entries(&self) -> &[Reservation] {
+        if self.entries.is_null() {
+            return &[];
+        }
+        unsafe { core::slice::from_raw_parts(self.entries, self.len) }
+    }
+
+    /// Claim the memory occupied by the bootloader stages and the page tables stage-1 set up
+    pub fn reserve_bootloader(&mut self) -> Result<()> {
+        for (owner, start, end) in BOOTLOADER_RANGES {
+            self.reserve(owner, start, end - start)?;
+        }
+        Ok(())
+    }
+
+    /// Claim `len` bytes starting at `start` for `owner`
+    pub fn reserve(&mut self, owner: Owner, start: u64, len: u64) -> Result<()> {
+        if len == 0 {
+            return Ok(());
+        }
+        if self.sealed {
+            return Err(Error::Sealed);
+        }
+        let new = Reservation { start, end: start + len, owner };
+        self.check_overlap(new)?;
+
+        if self.len == self.cap {
+            self.grow()?;
+        }
+        self.insert(new);
+        Ok(())
+    }
+
+    /// Claim every part of `[start, end)` for `owner` that is not claimed by anybody else yet
+    pub fn reserve_unclaimed(&mut self, owner: Owner, start: u64, end: u64) -> Result<()> {
+        let mut cur = start;
+        while cur < end {
+            let next = self.entries().iter().find(|r| r.end > cur && r.start < end).copied();
+            let gap_end = next.map_or(end, |r| core::cmp::max(r.start, cur));
+            self.reserve(owner, cur, gap_end - cur)?;
+            cur = next.map_or(end, |r| r.end);
+        }
+        Ok(())
+    }
+
+    /// Hand `[start, end)` over to `owner`, taking it away from the claims it overlaps with. Claims
+    /// that only partially overlap keep the rest of their range
+    pub fn reassign(&mut self, owner: Owner, start: u64, end: u64) -> Result<()> {
+        if start >= end {
+            return Ok(());
+        }
+        if self.sealed {
+            return Err(Error::Sealed);
+        }
+
+        // Splitting up a claim and inserting the new one add at most two entries
+        while self.len + 2 > self.cap {
+            self.grow()?;
+        }
+
+        while let Some(idx) = self.entries().iter().position(|r| r.start < end && start < r.end) {
+            let r = self.entries()[idx];
+            let entries = unsafe { core::slice::from_raw_parts_mut(self.entries, self.cap) };
+            entries.copy_within(idx + 1..self.len, idx);
+            self.len -= 1;
+
+            if r.start < start {
+                self.insert(Reservation { start: r.start, end: start, owner: r.owner });
+            }
+            if end < r.end {
+                self.insert(Reservation { start: end, end: r.end, owner: r.owner });
+            }
+        }
+        self.insert(Reservation { start, end, owner });
+        Ok(())
+    }
+
+    /// Insert `new` at its sorted position, merging it with adjacent claims of the same owner
+    fn insert(&mut self, new: Reservation) {
+        let idx = self.entries().iter().position(|r| r.start > new.start).unwrap_or(self.len);
+        let entries = unsafe { core::slice::from_raw_parts_mut(self.entries, self.cap) };
+
+        let merge_prev = idx > 0 && entries[idx - 1].owner == new.owner &&
+            entries[idx - 1].end == new.start;
+        let merge_next = idx < self.len && entries[idx].owner == new.owner &&
+            entries[idx].start == new.end;
+
+        match (merge_prev, merge_next) {
+            (true, true) => {
+                entries[idx - 1].end = entries[idx].end;
+                entries.copy_within(idx + 1..self.len, idx);
+                self.len -= 1;
+            }
+            (true, false) => entries[idx - 1].end = new.end,
+            (false, true) => entries[idx].start = new.start,
+            (false, false) => {
+                entries.copy_within(idx..self.len, idx + 1);
+                entries[idx] = new;
+                self.len += 1;
+            }
+        }
+    }
+
+    /// Move the registry to storage twice its current size, and claim that storage
+    fn grow(&mut self) -> Result<()> {
+        let cur_pages = ((self.cap * size_of::<Reservation>()) as u64).div_ceil(PAGE_SIZE);
+        let pages     = core::cmp::max(1, cur_pages * 2);
+        let addr  = FRAME_ALLOCATOR.lock().alloc_frames(pages).ok_or(Error::OutOfMemory)?;
+
+        let entries = addr as *mut Reservation;
+        if !self.entries.is_null() {
+            unsafe { core::ptr::copy_nonoverlapping(self.entries, entries, self.len) };
+        }
+        self.entries = entries;
+        self.cap     = (pages * PAGE_SIZE) as usize / size_of::<Reservation>();
+
+        // The old storage stays claimed, the frame allocator can't take memory back
+        let new = Reservation { start: addr, end: addr + pages * PAGE_SIZE, owner: Owner::Registry };
+        self.check_overlap(new)?;
+        self.insert(new);
+        Ok(())
+    }
+
+    /// Make sure `new` does not overlap with any existing claim
+    fn check_overlap(&self, new: Reservation) -> Result<()> {
+        match self.entries().iter().find(|r| r.start < new.end && new.start < r.end) {
+            Some(existing) => Err(Error::Overlap { new, existing: *existing }),
+            None => Ok(()),
+        }
+    }
+
+    /// Print the firmware memory map alongside the ranges claimed within each region. Nothing may
+    /// be allocated on the heap here, the heap records its growth in the registry
+    pub fn print(&self, e820: &[E820Entry]) {
+        println!("Physical memory map:");
+        for entry in e820 {
+            let start = entry.base;
+            let end   = entry.base + entry.length;
+            println!("[{:0>16X}:{:0>16X}] {}", start, end, e820_type_name(entry.typ));
+
+            for r in self.entries().iter().filter(|r| r.start < end && start < r.end) {
+                println!("    [{:0>16X}:{:0>16X}] {}", r.start, r.end, r.owner.name());
+            }
+        }
+
+        // Claims that are not covered by any region reported by the firmware
+        for r in self.entries() {
+            if !e820.iter().any(|e| r.start < e.base + e.length && e.base < r.end) {
+                println!("[{:0>16X}:{:0>16X}] {} (not in memory map)", r.start, r.end,
+                         r.owner.name());
+            }
+        }
+    }
+}
+
+/// Name of an E820 region type
+fn e820_type_name(typ: u32) -> &'static str {
+    match typ {
+        1 => "usable",
+        2 => "reserved",
+        3 => "acpi reclaimable",
+        4 => "acpi nvs",
+        5 => "bad memory",
+        _ => "unknown",
+    }
+}
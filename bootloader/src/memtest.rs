@@ -0,0 +1,128 @@
+//! Memory self-test
+//!     - Runs memtest-style patterns over the memory pool of every core, before the core is started
+//!       and uses it
+//!     - Pools come from the frame allocator after all exclusions, so the patterns never touch
+//!       ranges devices use for DMA or anything the bootloader allocated
+//!     - Every page that fails is claimed as bad memory in the memory registry right away, so the
+//!       kernel never uses it. The core itself only keeps the largest run of good pages of its
+//!       pool, so flaky RAM can't show up as fuzzing crashes
+
+use crate::{
+    println,
+    mm::PAGE_SIZE,
+    memmap::{Owner, REGISTRY},
+    smp::CoreInfo,
+};
+
+use core::ptr::{read_volatile, write_volatile};
+
+/// Claims the pages that fail a test as bad memory
+#[derive(Default)]
+struct BadPages {
+    /// Last page that was claimed, a failing page usually fails for many words in a row
+    last: Option<u64>,
+}
+
+impl BadPages {
+    /// Record the page containing `addr` as bad
+    fn add(&mut self, addr: u64) {
+        let page = addr & !(PAGE_SIZE - 1);
+        if self.last == Some(page) {
+            return;
+        }
+        self.last = Some(page);
+
+        if let Err(v) = REGISTRY.lock().reassign(Owner::BadMemory, page, page + PAGE_SIZE) {
+            panic!("Failed to claim bad memory at {:#x}: {:?}", page, v);
+        }
+    }
+}
+
+/// Test the memory pool of `core`, then shrink the pool to its largest run of good pages. Returns
+/// the number of bad bytes found
+///
+/// # Safety
+/// The pool has to be identity mapped, and the core may not have been started yet
+pub unsafe fn test_core(core: &mut CoreInfo, seed: u64) -> u64 {
+    let (start, end) = (core.pool_start, core.pool_end);
+    println!("Testing memory of core {} [{:0>16X}:{:0>16X}]", core.index, start, end);
+
+    let mut bad = BadPages::default();
+    walking_ones(start, end, &mut bad);
+    address_in_address(start, end, &mut bad);
+    random(start, end, seed, &mut bad);
+
+    // The registry is sorted, so the good runs lie between the bad ranges
+    let mut total     = 0;
+    let mut best      = (start, start);
+    let mut run_start = start;
+    for r in REGISTRY.lock().entries() {
+        if r.owner != Owner::BadMemory || r.end <= start || end <= r.start {
+            continue;
+        }
+        let (bad_start, bad_end) = (core::cmp::max(r.start, start), core::cmp::min(r.end, end));
+        println!("Bad memory [{:0>16X}:{:0>16X}]", bad_start, bad_end);
+        total += bad_end - bad_start;
+
+        if bad_start - run_start > best.1 - best.0 {
+            best = (run_start, bad_start);
+        }
+        run_start = bad_end;
+    }
+    if end - run_start > best.1 - best.0 {
+        best = (run_start, end);
+    }
+
+    core.pool_start = best.0;
+    core.pool_end   = best.1;
+    println!("Core {} self-test done, found {:#x} bad bytes", core.index, total);
+    total
+}
+
+/// Write a single set bit, walking it through all 64 bit positions, to every word
+unsafe fn walking_ones(start: u64, end: u64, bad: &mut BadPages) {
+    for bit in 0..64 {
+        fill_and_verify(start, end, bad, |_| 1u64 << bit);
+    }
+}
+
+/// Write the address of every word into the word itself, catches address line faults
+unsafe fn address_in_address(start: u64, end: u64, bad: &mut BadPages) {
+    fill_and_verify(start, end, bad, |addr| addr);
+}
+
+/// Fill memory from a seeded pseudo random sequence, then replay the sequence to verify it
+unsafe fn random(start: u64, end: u64, seed: u64, bad: &mut BadPages) {
+    // Xorshift gets stuck on a zero state
+    let mut state = seed | 1;
+    for addr in (start..end).step_by(8) {
+        write_volatile(addr as *mut u64, xorshift(&mut state));
+    }
+
+    let mut state = seed | 1;
+    for addr in (start..end).step_by(8) {
+        if read_volatile(addr as *const u64) != xorshift(&mut state) {
+            bad.add(addr);
+        }
+    }
+}
+
+/// Write `pattern(addr)` to every word in `[start, end)`, then read all of them back
+unsafe fn fill_and_verify(start: u64, end: u64, bad: &mut BadPages, pattern: impl Fn(u64) -> u64) {
+    for addr in (start..end).step_by(8) {
+        write_volatile(addr as *mut u64, pattern(addr));
+    }
+    for addr in (start..end).step_by(8) {
+        if read_volatile(addr as *const u64) != pattern(addr) {
+            bad.add(addr);
+        }
+    }
+}
+
+/// Advance the xorshift64 state and return the next value
+fn xorshift(state: &mut u64) -> u64 {
+    *state ^= *state << 13;
+    *state ^= *state >> 7;
+    *state ^= *state << 17;
+    *state
+}
@@ -1,3 +1,27 @@
+use crate::{
+    memmap::{Owner, REGISTRY},
+    physmem::{self, Pod, PhysMem},
+};
+
+use spin::Mutex;
+
+/// Size of a single physical frame/page
+pub const PAGE_SIZE: u64 = 0x1000;
+
+/// Memory below 1MiB is left untouched by the allocator. It contains the IVT, BIOS data, the
+/// bootloader stages, the initial page tables and the AP entry point
+const ALLOC_START: u64 = 0x100000;
+
+/// Maximum number of E820 entries stage-1 can hand us
+pub const MAX_E820_ENTRIES: usize = 32;
+
+/// Maximum number of usable regions the frame allocator can track. Leaves room for regions to be
+/// split up when ranges are excluded from them
+pub const MAX_REGIONS: usize = MAX_E820_ENTRIES * 2;
+
+/// Global physical frame allocator, initialized by the BSP from the E820 memory map
+pub static FRAME_ALLOCATOR: Mutex<FrameAllocator> = Mutex::new(FrameAllocator::empty());
+
 #[repr(packed, C)]
 #[derive(Debug, Default, Copy, Clone)]
 /// Memory mappings detected by BIOS 0x15 function
@@ -11,36 +35,159 @@ pub struct E820Entry {
     pub typ:  u32,
 }
 
+unsafe impl Pod for E820Entry {}
+
+#[repr(packed, C)]
+#[derive(Copy, Clone)]
+/// Memory map stage-1 retrieves from the BIOS and passes to stage-2
+pub struct MemLayout {
+    pub num_entries: u64,
+    pub mem_layout: [E820Entry; MAX_E820_ENTRIES],
+}
+
+unsafe impl Pod for MemLayout {}
+
+impl MemLayout {
+    /// Read the memory map stage-1 placed at physical address `addr`
+    pub fn read(mem: &impl PhysMem, addr: u64) -> physmem::Result<Self> {
+        mem.read::<Self>(addr)
+    }
+
+    /// Entries that were actually filled in by stage-1
+    pub fn entries(&self) -> &[E820Entry] {
+        let num = core::cmp::min(self.num_entries as usize, MAX_E820_ENTRIES);
+        &self.mem_layout[..num]
+    }
+}
+
+/// A range of usable physical memory `[start, end)`
+#[derive(Debug, Default, Copy, Clone)]
+struct Region {
+    start: u64,
+    end:   u64,
+}
+
+/// Bump allocator that hands out page-aligned physical frames from the usable E820 regions
 #[derive(Debug)]
-pub struct MMU {
-    pub free_mem_region: [E820Entry; 50],
-    pub count: i32,
+pub struct FrameAllocator {
+    regions:     [Region; MAX_REGIONS],
+    num_regions: usize,
 }
 
-impl MMU {
-    pub fn init(_addr: usize) -> Option<MMU> {
-        /*
-        let count: i32 = unsafe { *(addr as *const i32) };
-        let mut _total_mem: u64 = 0;
-        let mem_map: &[E820Entry] = unsafe { &*((addr+8) as *mut [E820Entry; 50]) };
-        let mut free_region_count: u32 = 0;
+impl FrameAllocator {
+    /// Allocator without any memory to hand out
+    pub const fn empty() -> Self {
+        Self {
+            regions:     [Region { start: 0, end: 0 }; MAX_REGIONS],
+            num_regions: 0,
+        }
+    }
+
+    /// Initialize the allocator from the memory map retrieved by stage-1. Only usable regions
+    /// above `ALLOC_START` are considered, and they are trimmed to page boundaries
+    pub fn init(&mut self, mem_layout: &[E820Entry]) {
+        *self = Self::empty();
+
+        for entry in mem_layout {
+            if { entry.typ } != 1 {
+                continue;
+            }
+
+            let start = core::cmp::max(entry.base, ALLOC_START);
+            let start = (start + PAGE_SIZE - 1) & !(PAGE_SIZE - 1);
+            let end   = (entry.base + entry.length) & !(PAGE_SIZE - 1);
+            if start >= end || self.num_regions >= MAX_REGIONS {
+                continue;
+            }
+
+            self.regions[self.num_regions] = Region { start, end };
+            self.num_regions += 1;
+        }
+
+        // Hand out low memory first. The initial page tables from stage-1 only map the first GiB,
+        // so the first allocations need to come from there
+        self.regions[..self.num_regions].sort_unstable_by_key(|r| r.start);
+    }
 
-        let mut mmu = MMU { free_mem_region: [E820Entry::default(); 50], count };
+    /// Allocate `num` physically contiguous frames, returning the physical address of the first
+    pub fn alloc_frames(&mut self, num: u64) -> Option<u64> {
+        let size = num.checked_mul(PAGE_SIZE)?;
+        self.regions[..self.num_regions].iter_mut()
+            .find(|r| r.end - r.start >= size)
+            .map(|r| {
+                let addr = r.start;
+                r.start += size;
+                addr
+            })
+    }
 
-        assert!(count <= 50);
+    /// Free range `[start, end)` of the `idx`th region, `None` once `idx` is past the last region
+    pub fn free_region(&self, idx: usize) -> Option<(u64, u64)> {
+        self.regions[..self.num_regions].get(idx).map(|r| (r.start, r.end))
+    }
 
-        for i in 0..count {
-            let ii = i as usize;
+    /// Never hand out any memory in `[start, end)`. Regions that partially overlap are trimmed or
+    /// split up. If there is no room left to split a region, the smaller half is dropped
+    pub fn exclude(&mut self, start: u64, end: u64) {
+        let start = start & !(PAGE_SIZE - 1);
+        let end   = (end + PAGE_SIZE - 1) & !(PAGE_SIZE - 1);
 
-            if mem_map[ii].typ == 1 {
-                //mmu.free_mem_region[free_region_count as usize].addr = mem_map[ii].base;
-                //mmu.free_mem_region[free_region_count as usize].len = mem_map[ii].length;
-                //_total_mem += mem_map[ii].length;
-                //free_region_count += 1;
+        let mut idx = 0;
+        while idx < self.num_regions {
+            let r = self.regions[idx];
+            if r.start >= end || start >= r.end {
+                idx += 1;
+                continue;
+            }
+
+            let low  = Region { start: r.start, end: start };
+            let high = Region { start: end, end: r.end };
+            match (low.start < low.end, high.start < high.end) {
+                (true, true) if self.num_regions < MAX_REGIONS => {
+                    self.regions.copy_within(idx + 1..self.num_regions, idx + 2);
+                    self.regions[idx]     = low;
+                    self.regions[idx + 1] = high;
+                    self.num_regions += 1;
+                    idx += 2;
+                }
+                (true, true) => {
+                    self.regions[idx] = if low.end - low.start >= high.end - high.start {
+                        low
+                    } else {
+                        high
+                    };
+                    idx += 1;
+                }
+                (true, false) => {
+                    self.regions[idx] = low;
+                    idx += 1;
+                }
+                (false, true) => {
+                    self.regions[idx] = high;
+                    idx += 1;
+                }
+                (false, false) => {
+                    self.regions.copy_within(idx + 1..self.num_regions, idx);
+                    self.num_regions -= 1;
+                }
             }
         }
-        mmu
-        */
-        None
     }
+
+    /// Highest physical address of usable memory that the allocator knows about
+    pub fn max_phys_addr(&self) -> u64 {
+        self.regions[..self.num_regions].iter().map(|r| r.end).max().unwrap_or(0)
+    }
+}
+
+/// Allocate `num` physically contiguous frames from the global frame allocator and claim them for
+/// `owner` in the memory registry
+pub fn alloc_frames(num: u64, owner: Owner) -> Option<u64> {
+    let addr = FRAME_ALLOCATOR.lock().alloc_frames(num)?;
+
+    // The frame allocator handing out memory somebody else claimed is a bug, not a runtime error
+    if let Err(err) = REGISTRY.lock().reserve(owner, addr, num * PAGE_SIZE) {
+        panic!("{:?}", err);
+    }
+    Some(addr)
 }
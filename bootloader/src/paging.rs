@@ -0,0 +1,194 @@
+//! 4-level x86_64 page tables
+//!     - Stage-1 only maps the first GiB using a single 1GiB page. Stage-2 builds its own tables
+//!       so memory can be mapped at 4KiB granularity (eg. stack guard pages)
+
+use crate::{
+    mm::{self, PAGE_SIZE, FRAME_ALLOCATOR},
+    memmap::Owner,
+    physmem::{self, PhysMem},
+    tlb::{self, Flush},
+};
+
+/// Entry is present
+pub const PAGE_PRESENT: u64 = 1 << 0;
+
+/// Entry is writable
+pub const PAGE_WRITE: u64 = 1 << 1;
+
+/// Entry maps a large page (2MiB in a PD, 1GiB in a PDPT)
+pub const PAGE_HUGE: u64 = 1 << 7;
+
+/// Mask to extract the physical address from a page table entry
+const ADDR_MASK: u64 = 0x000f_ffff_ffff_f000;
+
+/// Size of a page mapped by a page directory entry
+const LARGE_PAGE_SIZE: u64 = 0x20_0000;
+
+/// Lowest 4GiB are always identity mapped since they contain MMIO regions such as the local APIC
+const MIN_IDENTITY_MAP: u64 = 0x1_0000_0000;
+
+/// Range identity mapped by the initial page tables stage-1 sets up
+pub const STAGE1_IDENTITY_MAP: u64 = 0x4000_0000;
+
+pub type Result<T> = core::result::Result<T, Error>;
+#[derive(Debug)]
+pub enum Error {
+    /// Frame allocator ran out of memory while allocating a page table
+    OutOfMemory,
+
+    /// Tried to unmap an address that is not currently mapped
+    NotMapped,
+
+    /// Page table memory could not be accessed
+    PhysMem(physmem::Error),
+
+    /// Other cores could not be made to drop the old translation
+    Shootdown(tlb::Error),
+}
+
+impl From<physmem::Error> for Error {
+    fn from(err: physmem::Error) -> Self {
+        Error::PhysMem(err)
+    }
+}
+
+/// Root of a 4-level page table hierarchy
+#[derive(Debug)]
+pub struct PageTable<M: PhysMem> {
+    /// Physical address of the PML4
+    root: u64,
+
+    /// Accessor used to read and write the page table entries
+    mem: M,
+}
+
+impl<M: PhysMem> PageTable<M> {
+    /// Allocate a new empty page table, accessing its entries through `mem`
+    pub fn new(mem: M) -> Result<Self> {
+        let mut table = Self { root: 0, mem };
+        table.root = table.alloc_table()?;
+        Ok(table)
+    }
+
+    /// Create a page table that identity maps all of physical memory using 2MiB pages
+    pub fn identity(mem: M) -> Result<Self> {
+        let mut table = Self::new(mem)?;
+
+        let end = identity_map_size();
+        for addr in (0..end).step_by(LARGE_PAGE_SIZE as usize) {
+            let pd = table.walk(addr, 2)?;
+            let idx = Self::index(addr, 1);
+            table.mem.write(pd + idx * 8, addr | PAGE_PRESENT | PAGE_WRITE | PAGE_HUGE)?;
+        }
+        Ok(table)
+    }
+
+    /// Access the page table entries through `mem` from now on, eg. after switching to an address
+    /// space where physical memory is mapped differently
+    pub fn with_mem<N: PhysMem>(self, mem: N) -> PageTable<N> {
+        PageTable { root: self.root, mem }
+    }
+
+    /// Physical address of the PML4, suitable to be loaded into cr3
+    pub fn root(&self) -> u64 {
+        self.root
+    }
+
+    /// Load this page table into cr3
+    ///
+    /// # Safety
+    /// The page table has to map all code and data in use, including the current stack
+    pub unsafe fn switch_to(&self) {
+        x86::controlregs::cr3_write(self.root);
+    }
+
+    /// Map the 4KiB page at `vaddr` to `paddr`, splitting up any large pages in the way. Replacing
+    /// an existing mapping invalidates it on all cores using this page table
+    ///
+    /// # Safety
+    /// Nothing may still use the old mapping of `vaddr`
+    pub unsafe fn map(&mut self, vaddr: u64, paddr: u64, flags: u64) -> Result<()> {
+        let pt = self.walk(vaddr, 3)?;
+        let entry_addr = pt + Self::index(vaddr, 0) * 8;
+        let old = self.mem.read::<u64>(entry_addr)?;
+        self.mem.write(entry_addr, (paddr & ADDR_MASK) | flags | PAGE_PRESENT)?;
+
+        // Non-present entries are never cached
+        if old & PAGE_PRESENT != 0 {
+            self.invalidate(vaddr)?;
+        }
+        Ok(())
+    }
+
+    /// Unmap the 4KiB page at `vaddr`, splitting up any large pages in the way. The page is
+    /// invalidated on all cores using this page table
+    ///
+    /// # Safety
+    /// Nothing may still use the page at `vaddr`
+    pub unsafe fn unmap(&mut self, vaddr: u64) -> Result<()> {
+        let pt = self.walk(vaddr, 3)?;
+        let entry_addr = pt + Self::index(vaddr, 0) * 8;
+        if self.mem.read::<u64>(entry_addr)? & PAGE_PRESENT == 0 {
+            return Err(Error::NotMapped);
+        }
+        self.mem.write(entry_addr, 0u64)?;
+        self.invalidate(vaddr)
+    }
+
+    /// Drop the translation of the 4KiB page at `vaddr` on all cores using this page table
+    unsafe fn invalidate(&self, vaddr: u64) -> Result<()> {
+        tlb::shootdown(self.root, Flush::Range { start: vaddr, pages: 1 }).map_err(Error::Shootdown)
+    }
+
+    /// Index into the table at `level` (0 = PT, 3 = PML4) for `vaddr`
+    fn index(vaddr: u64, level: u32) -> u64 {
+        (vaddr >> (12 + 9 * level)) & 0x1ff
+    }
+
+    /// Walk `depth` levels down from the PML4 and return the physical address of the table
+    /// reached. Missing tables are allocated, and large pages are split into the next smaller size
+    fn walk(&mut self, vaddr: u64, depth: u32) -> Result<u64> {
+        let mut table = self.root;
+        for level in (4 - depth..4).rev() {
+            let entry_addr = table + Self::index(vaddr, level) * 8;
+            let entry = self.mem.read::<u64>(entry_addr)?;
+
+            table = if entry & PAGE_PRESENT == 0 {
+                let next = self.alloc_table()?;
+                self.mem.write(entry_addr, next | PAGE_PRESENT | PAGE_WRITE)?;
+                next
+            } else if entry & PAGE_HUGE != 0 {
+                // Replace the large page with a table of the next smaller page size that maps the
+                // same physical range with the same permissions
+                let next = self.alloc_table()?;
+                let base = entry & ADDR_MASK;
+                let (size, flags) = if level == 2 {
+                    (LARGE_PAGE_SIZE, entry & !ADDR_MASK)
+                } else {
+                    (PAGE_SIZE, entry & !ADDR_MASK & !PAGE_HUGE)
+                };
+                for i in 0..512 {
+                    self.mem.write(next + i * 8, (base + i * size) | flags)?;
+                }
+                self.mem.write(entry_addr, next | PAGE_PRESENT | PAGE_WRITE)?;
+                next
+            } else {
+                entry & ADDR_MASK
+            };
+        }
+        Ok(table)
+    }
+
+    /// Allocate a zeroed frame for a new table
+    fn alloc_table(&mut self) -> Result<u64> {
+        let table = mm::alloc_frames(1, Owner::PageTables).ok_or(Error::OutOfMemory)?;
+        self.mem.zero(table, PAGE_SIZE as usize)?;
+        Ok(table)
+    }
+}
+
+/// Size of the identity map `PageTable::identity` creates, all of physical memory but at least 4GiB
+pub fn identity_map_size() -> u64 {
+    let end = core::cmp::max(FRAME_ALLOCATOR.lock().max_phys_addr(), MIN_IDENTITY_MAP);
+    (end + LARGE_PAGE_SIZE - 1) & !(LARGE_PAGE_SIZE - 1)
+}
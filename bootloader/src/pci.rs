@@ -0,0 +1,281 @@
+//! PCI configuration space access
+//!     - `PciConfig` abstracts over how configuration space is reached, so bus enumeration does
+//!       not need to care
+//!     - `Ecam` uses the memory mapped regions from the acpi MCFG, `LegacyPci` falls back to the
+//!       0xcf8/0xcfc io ports, which only reach segment 0 and the first 256 bytes of a function
+
+use crate::{acpi::mcfg::EcamRegion, paging};
+
+use alloc::vec::Vec;
+use core::ptr::{read_volatile, write_volatile};
+use x86::io;
+
+/// Io ports of the legacy configuration mechanism
+const CONFIG_ADDRESS: u16 = 0xcf8;
+const CONFIG_DATA:    u16 = 0xcfc;
+
+/// Size of the configuration space of a function through ECAM
+const ECAM_CONFIG_SIZE: u16 = 0x1000;
+
+/// Size of the configuration space of a function through the legacy io ports
+const LEGACY_CONFIG_SIZE: u16 = 0x100;
+
+/// Vendor id read back for functions that do not exist
+const INVALID_VENDOR: u16 = 0xffff;
+
+pub type Result<T> = core::result::Result<T, Error>;
+#[derive(Debug)]
+pub enum Error {
+    /// No configuration space access method covers the bus of this function
+    BusNotCovered(PciAddress),
+
+    /// Offset is not 4-byte aligned or outside of the configuration space
+    InvalidOffset(u16),
+}
+
+/// Address of a single pci function
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PciAddress {
+    pub segment:  u16,
+    pub bus:      u8,
+    pub device:   u8,
+    pub function: u8,
+}
+
+/// Access to the configuration space of pci functions. Accesses are 32-bit wide and aligned
+pub trait PciConfig {
+    /// Read the register at `offset` of the function at `addr`
+    fn read32(&self, addr: PciAddress, offset: u16) -> Result<u32>;
+
+    /// Write `val` to the register at `offset` of the function at `addr`
+    fn write32(&mut self, addr: PciAddress, offset: u16, val: u32) -> Result<()>;
+
+    /// Read the 16-bit register at `offset`, which may be 2-byte aligned
+    fn read16(&self, addr: PciAddress, offset: u16) -> Result<u16> {
+        let val = self.read32(addr, offset & !3)?;
+        Ok((val >> ((offset & 2) * 8)) as u16)
+    }
+
+    /// Read the 8-bit register at `offset`
+    fn read8(&self, addr: PciAddress, offset: u16) -> Result<u8> {
+        let val = self.read32(addr, offset & !3)?;
+        Ok((val >> ((offset & 3) * 8)) as u8)
+    }
+
+    /// Write the 8-bit register at `offset`, preserving the rest of its dword
+    fn write8(&mut self, addr: PciAddress, offset: u16, val: u8) -> Result<()> {
+        let shift = (offset & 3) * 8;
+        let old   = self.read32(addr, offset & !3)?;
+        let new   = (old & !(0xff << shift)) | ((val as u32) << shift);
+        self.write32(addr, offset & !3, new)
+    }
+
+    /// Vendor and device id of the function at `addr`, if it exists
+    fn ids(&self, addr: PciAddress) -> Option<(u16, u16)> {
+        let val = self.read32(addr, 0).ok()?;
+        match val as u16 {
+            INVALID_VENDOR => None,
+            vendor => Some((vendor, (val >> 16) as u16)),
+        }
+    }
+
+    /// Segments and bus ranges this accessor can reach
+    fn buses(&self) -> Vec<(u16, u8, u8)>;
+
+    /// Call `f` with the address of every function present on any reachable bus. Buses are
+    /// scanned by brute force, so this also finds functions behind unconfigured bridges
+    fn for_each_function(&self, mut f: impl FnMut(PciAddress)) where Self: Sized {
+        for (segment, start_bus, end_bus) in self.buses() {
+            for bus in start_bus..=end_bus {
+                for device in 0..32 {
+                    let addr = PciAddress { segment, bus, device, function: 0 };
+                    if self.ids(addr).is_none() {
+                        continue;
+                    }
+
+                    // Only multi-function devices implement functions other than 0
+                    let header_type = self.read8(addr, 0xe).unwrap_or(0);
+                    let functions   = if (header_type & 0x80) != 0 { 8 } else { 1 };
+                    for function in 0..functions {
+                        let addr = PciAddress { function, ..addr };
+                        if self.ids(addr).is_some() {
+                            f(addr);
+                        }
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// Make sure `offset` is a 4-byte aligned register within a configuration space of `size` bytes
+fn check_offset(offset: u16, size: u16) -> Result<()> {
+    if (offset & 3) != 0 || offset >= size {
+        return Err(Error::InvalidOffset(offset));
+    }
+    Ok(())
+}
+
+/// Memory mapped configuration space access
+pub struct Ecam {
+    regions: Vec<EcamRegion>,
+}
+
+impl Ecam {
+    /// Access configuration space through `regions`. Regions outside of the identity map are
+    /// dropped
+    ///
+    /// # Safety
+    /// `regions` have to describe actual ECAM regions, as reported by the firmware
+    pub unsafe fn new(regions: &[EcamRegion]) -> Self {
+        let limit = paging::identity_map_size();
+        Self {
+            regions: regions.iter().copied().filter(|r| r.end() <= limit).collect(),
+        }
+    }
+
+    /// Physical address of the register at `offset` of the function at `addr`
+    fn register(&self, addr: PciAddress, offset: u16) -> Result<u64> {
+        check_offset(offset, ECAM_CONFIG_SIZE)?;
+        let region = self.regions.iter()
+            .find(|r| r.segment == addr.segment && (r.start_bus..=r.end_bus).contains(&addr.bus))
+            .ok_or(Error::BusNotCovered(addr))?;
+
+        Ok(region.bus_addr(addr.bus) +
+           ((addr.device as u64 & 0x1f) << 15) +
+           ((addr.function as u64 & 0x7) << 12) +
+           offset as u64)
+    }
+}
+
+impl PciConfig for Ecam {
+    fn read32(&self, addr: PciAddress, offset: u16) -> Result<u32> {
+        let reg = self.register(addr, offset)?;
+        Ok(unsafe { read_volatile(reg as *const u32) })
+    }
+
+    fn write32(&mut self, addr: PciAddress, offset: u16, val: u32) -> Result<()> {
+        let reg = self.register(addr, offset)?;
+        unsafe { write_volatile(reg as *mut u32, val) };
+        Ok(())
+    }
+
+    fn buses(&self) -> Vec<(u16, u8, u8)> {
+        self.regions.iter().map(|r| (r.segment, r.start_bus, r.end_bus)).collect()
+    }
+}
+
+/// Configuration space access through the legacy io ports
+pub struct LegacyPci;
+
+impl LegacyPci {
+    /// Value selecting the register at `offset` of the function at `addr`
+    fn select(addr: PciAddress, offset: u16) -> Result<u32> {
+        check_offset(offset, LEGACY_CONFIG_SIZE)?;
+        if addr.segment != 0 {
+            return Err(Error::BusNotCovered(addr));
+        }
+
+        Ok(0x8000_0000 |
+           ((addr.bus as u32) << 16) |
+           ((addr.device as u32 & 0x1f) << 11) |
+           ((addr.function as u32 & 0x7) << 8) |
+           offset as u32)
+    }
+}
+
+impl PciConfig for LegacyPci {
+    fn read32(&self, addr: PciAddress, offset: u16) -> Result<u32> {
+        let select = Self::select(addr, offset)?;
+        unsafe {
+            io::outl(CONFIG_ADDRESS, select);
+            Ok(io::inl(CONFIG_DATA))
+        }
+    }
+
+    fn write32(&mut self, addr: PciAddress, offset: u16, val: u32) -> Result<()> {
+        let select = Self::select(addr, offset)?;
+        unsafe {
+            io::outl(CONFIG_ADDRESS, select);
+            io::outl(CONFIG_DATA, val);
+        }
+        Ok(())
+    }
+
+    fn buses(&self) -> Vec<(u16, u8, u8)> {
+        alloc::vec![(0, 0, 255)]
+    }
+}
+
+/// Best configuration space access available, ECAM if the firmware provides it and legacy io
+/// otherwise
+pub enum Pci {
+    Ecam(Ecam),
+    Legacy(LegacyPci),
+}
+
+impl Pci {
+    /// Pick the access method based on the ECAM regions found in the MCFG
+    ///
+    /// # Safety
+    /// `regions` have to describe actual ECAM regions, as reported by the firmware
+    pub unsafe fn new(regions: &[EcamRegion]) -> Self {
+        let ecam = Ecam::new(regions);
+        if ecam.regions.is_empty() {
+            Pci::Legacy(LegacyPci)
+        } else {
+            Pci::Ecam(ecam)
+        }
+    }
+}
+
+impl PciConfig for Pci {
+    fn read32(&self, addr: PciAddress, offset: u16) -> Result<u32> {
+        match self {
+            Pci::Ecam(v)   => v.read32(addr, offset),
+            Pci::Legacy(v) => v.read32(addr, offset),
+        }
+    }
+
+    fn write32(&mut self, addr: PciAddress, offset: u16, val: u32) -> Result<()> {
+        match self {
+            Pci::Ecam(v)   => v.write32(addr, offset, val),
+            Pci::Legacy(v) => v.write32(addr, offset, val),
+        }
+    }
+
+    fn buses(&self) -> Vec<(u16, u8, u8)> {
+        match self {
+            Pci::Ecam(v)   => v.buses(),
+            Pci::Legacy(v) => v.buses(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ecam_register() {
+        let ecam = Ecam {
+            regions: alloc::vec![
+                EcamRegion { base: 0xb000_0000, segment: 0, start_bus: 0,    end_bus: 0x3f },
+                EcamRegion { base: 0xc000_0000, segment: 1, start_bus: 0x80, end_bus: 0xff },
+            ],
+        };
+        let addr = |segment, bus, device, function| PciAddress { segment, bus, device, function };
+
+        assert_eq!(ecam.register(addr(0, 0, 0, 0), 0).unwrap(), 0xb000_0000);
+        assert_eq!(ecam.register(addr(0, 0x3f, 31, 7), 0xffc).unwrap(), 0xb3ff_fffc);
+
+        // The base of a region is where bus 0 would be, not its first bus
+        assert_eq!(ecam.register(addr(1, 0x80, 0, 0), 0).unwrap(), 0xc800_0000);
+        assert_eq!(ecam.register(addr(1, 0x81, 2, 1), 0x10).unwrap(), 0xc811_1010);
+
+        assert!(matches!(ecam.register(addr(0, 0x40, 0, 0), 0), Err(Error::BusNotCovered(_))));
+        assert!(matches!(ecam.register(addr(1, 0x7f, 0, 0), 0), Err(Error::BusNotCovered(_))));
+        assert!(matches!(ecam.register(addr(0, 0, 0, 0), 2), Err(Error::InvalidOffset(2))));
+        assert!(matches!(ecam.register(addr(0, 0, 0, 0), 0x1000), Err(Error::InvalidOffset(_))));
+    }
+}
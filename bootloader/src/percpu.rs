@@ -0,0 +1,170 @@
+//! Per-core data
+//!     - Every core gets a `PerCpu` block at the start of its memory pool, `IA32_GS_BASE` points to
+//!       it
+//!     - Only the owning core ever touches its block, so nothing in it is locked or atomic. Every
+//!       field has a single writer, interrupt handlers of the core only read what the interrupted
+//!       code writes, and they read it volatile
+//!     - The block starts with a pointer to itself, so `current` is a single load through `gs`.
+//!       Until `init` ran, `IA32_GS_BASE` points at a null pointer instead
+
+use crate::{
+    mm::PAGE_SIZE,
+    smp::CoreInfo,
+    stack::Stack,
+};
+
+use core::{
+    alloc::Layout,
+    cell::Cell,
+    ptr::{read_volatile, NonNull},
+    mem::{offset_of, size_of},
+};
+use x86::msr;
+
+/// Counter of a single core, only ever updated from one context of that core
+#[derive(Debug, Default)]
+pub struct Counter(Cell<u64>);
+
+impl Counter {
+    /// Add `n` to the counter
+    pub fn add(&self, n: u64) {
+        self.0.set(self.0.get() + n);
+    }
+
+    /// Current value, can also be read from an interrupt handler while the core updates it
+    pub fn get(&self) -> u64 {
+        unsafe { read_volatile(self.0.as_ptr()) }
+    }
+}
+
+/// Counters a core keeps about itself
+#[derive(Debug, Default)]
+pub struct Stats {
+    /// Number of allocations served from the core's pool
+    pub allocations:     Counter,
+
+    /// Bytes handed out from the core's pool, including alignment padding
+    pub allocated_bytes: Counter,
+
+    /// Number of commands received from other cores, updated by the command handler
+    pub commands:        Counter,
+}
+
+/// Bump allocator over the memory pool of a core. Interrupt handlers must not allocate
+#[derive(Debug)]
+pub struct LocalAllocator {
+    /// Next free address
+    next: Cell<u64>,
+
+    /// End of the pool
+    end:  u64,
+}
+
+impl LocalAllocator {
+    /// Allocate memory for `layout`, `None` once the pool is exhausted. Memory is never freed
+    pub fn alloc(&self, layout: Layout) -> Option<NonNull<u8>> {
+        let align = layout.align() as u64;
+        let start = self.next.get().checked_add(align - 1)? & !(align - 1);
+        let end   = start.checked_add(layout.size() as u64)?;
+        if end > self.end {
+            return None;
+        }
+        self.next.set(end);
+        NonNull::new(start as *mut u8)
+    }
+
+    /// Allocate `num` page-aligned frames, returning the physical address of the first
+    pub fn alloc_frames(&self, num: u64) -> Option<u64> {
+        let size   = usize::try_from(num.checked_mul(PAGE_SIZE)?).ok()?;
+        let layout = Layout::from_size_align(size, PAGE_SIZE as usize).ok()?;
+        self.alloc(layout).map(|ptr| ptr.as_ptr() as u64)
+    }
+
+    /// Bytes left in the pool, can also be read from an interrupt handler
+    pub fn remaining(&self) -> u64 {
+        self.end - unsafe { read_volatile(self.next.as_ptr()) }
+    }
+}
+
+/// Data private to a single core, reached through `IA32_GS_BASE`
+#[repr(C)]
+#[derive(Debug)]
+pub struct PerCpu {
+    /// Address of this block, `current` reads it through `gs:[0]`
+    this:          *const PerCpu,
+
+    /// Index of the core, see `CoreInfo::index`
+    pub index:     u32,
+    pub apic_id:   u32,
+
+    /// Parameters the core was started with
+    pub core:      &'static CoreInfo,
+
+    /// Stack the core is running on
+    pub stack:     Stack,
+
+    /// Allocator over the rest of the core's memory pool
+    pub allocator: LocalAllocator,
+    pub stats:     Stats,
+}
+
+const _: () = assert!(offset_of!(PerCpu, this) == 0);
+
+/// What `gs:[0]` reads on cores that did not call `init` yet
+static NO_PERCPU: u64 = 0;
+
+impl PerCpu {
+    /// Allocate memory for `layout` from this core's pool and account for it in the stats
+    pub fn alloc(&self, layout: Layout) -> Option<NonNull<u8>> {
+        let before = self.allocator.remaining();
+        let ptr = self.allocator.alloc(layout)?;
+
+        self.stats.allocations.add(1);
+        self.stats.allocated_bytes.add(before - self.allocator.remaining());
+        Some(ptr)
+    }
+}
+
+/// Set up the per-core block of `core` at the start of its memory pool and point `IA32_GS_BASE`
+/// at it
+///
+/// # Safety
+/// Has to be called once, on the core described by `core`. Its pool has to be identity mapped and
+/// not be in use yet
+pub unsafe fn init(core: &'static CoreInfo) -> &'static PerCpu {
+    let addr = core.pool_start;
+    let pool = (addr + size_of::<PerCpu>() as u64 + PAGE_SIZE - 1) & !(PAGE_SIZE - 1);
+    assert!(pool <= core.pool_end, "Memory pool too small for the per-core data");
+
+    let percpu = addr as *mut PerCpu;
+    percpu.write(PerCpu {
+        this:      percpu,
+        index:     core.index,
+        apic_id:   core.apic_id,
+        core,
+        stack:     core.stack,
+        allocator: LocalAllocator { next: Cell::new(pool), end: core.pool_end },
+        stats:     Stats::default(),
+    });
+    msr::wrmsr(msr::IA32_GS_BASE, addr);
+    &*percpu
+}
+
+/// Point `IA32_GS_BASE` at a null pointer, so `current` returns `None` until `init` is called.
+/// Firmware may leave any value in it
+///
+/// # Safety
+/// Has to be called before `init` on the same core
+pub unsafe fn clear() {
+    msr::wrmsr(msr::IA32_GS_BASE, &NO_PERCPU as *const u64 as u64);
+}
+
+/// Per-core block of the core this is running on, `None` if `init` was not called on it yet
+pub fn current() -> Option<&'static PerCpu> {
+    let this: *const PerCpu;
+    unsafe {
+        core::arch::asm!("mov {}, gs:[0]", out(reg) this,
+                         options(nostack, readonly, preserves_flags));
+        this.as_ref()
+    }
+}
@@ -0,0 +1,349 @@
+//! Physical memory accessors
+//!     - All accesses are bounds-checked against the range the accessor was created for
+//!     - Typed reads/writes go through byte copies, so unaligned addresses are fine
+//!     - Mapped memory may be MMIO, so `IdentityMem` and `OffsetMem` only use volatile accesses.
+//!       Typed accesses of 1, 2, 4 or 8 bytes at naturally aligned addresses are a single access of
+//!       that width, the way device registers expect them
+//!     - `IdentityMem` is used while physical memory is identity mapped, `OffsetMem` once physical
+//!       memory is only reachable through a direct-map at some offset, and `SliceMem` backs the
+//!       accessor with a byte buffer so code using it can run on the host
+
+use core::mem::{size_of, MaybeUninit};
+use core::ptr::{read_volatile, write_volatile};
+
+pub type Result<T> = core::result::Result<T, Error>;
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Error {
+    /// Access to `len` bytes at physical address `addr` is outside of the accessible range
+    OutOfBounds { addr: u64, len: usize },
+}
+
+/// Types that consist of plain bytes, where every bit-pattern is a valid value. Only these can be
+/// read from or written to physical memory
+///
+/// # Safety
+/// The type may not contain padding, references, or fields with invalid bit-patterns
+pub unsafe trait Pod: Copy {}
+
+unsafe impl Pod for u8 {}
+unsafe impl Pod for u16 {}
+unsafe impl Pod for u32 {}
+unsafe impl Pod for u64 {}
+unsafe impl<T: Pod, const N: usize> Pod for [T; N] {}
+
+/// Access to physical memory
+pub trait PhysMem {
+    /// Copy `buf.len()` bytes starting at physical address `addr` into `buf`
+    fn read_bytes(&self, addr: u64, buf: &mut [u8]) -> Result<()>;
+
+    /// Copy `buf` into physical memory starting at physical address `addr`
+    fn write_bytes(&mut self, addr: u64, buf: &[u8]) -> Result<()>;
+
+    /// Read a `T` from physical address `addr`
+    fn read<T: Pod>(&self, addr: u64) -> Result<T> {
+        let mut val = MaybeUninit::<T>::zeroed();
+        let bytes = unsafe {
+            core::slice::from_raw_parts_mut(val.as_mut_ptr() as *mut u8, size_of::<T>())
+        };
+        self.read_bytes(addr, bytes)?;
+
+        // Any bit-pattern is valid for `Pod` types
+        Ok(unsafe { val.assume_init() })
+    }
+
+    /// Write `val` to physical address `addr`
+    fn write<T: Pod>(&mut self, addr: u64, val: T) -> Result<()> {
+        let bytes = unsafe {
+            core::slice::from_raw_parts(&val as *const T as *const u8, size_of::<T>())
+        };
+        self.write_bytes(addr, bytes)
+    }
+
+    /// Zero out `len` bytes starting at physical address `addr`
+    fn zero(&mut self, addr: u64, len: usize) -> Result<()> {
+        let zeroes = [0u8; 64];
+        for off in (0..len).step_by(zeroes.len()) {
+            let size = core::cmp::min(zeroes.len(), len - off);
+            self.write_bytes(addr + off as u64, &zeroes[..size])?;
+        }
+        Ok(())
+    }
+}
+
+/// Verify that `len` bytes at `addr` lie within `[start, end)`
+fn check_bounds(start: u64, end: u64, addr: u64, len: usize) -> Result<()> {
+    match addr.checked_add(len as u64) {
+        Some(access_end) if addr >= start && access_end <= end => Ok(()),
+        _ => Err(Error::OutOfBounds { addr, len }),
+    }
+}
+
+/// Read a `T` from `ptr` using volatile accesses, a single one if `T` has a natural access width
+///
+/// # Safety
+/// `ptr` has to be valid for reading `size_of::<T>()` bytes
+unsafe fn read_mapped<T: Pod>(ptr: *const u8) -> T {
+    let mut val = MaybeUninit::<T>::zeroed();
+    let out = val.as_mut_ptr() as *mut u8;
+    let aligned = (ptr as usize).is_multiple_of(size_of::<T>());
+    match size_of::<T>() {
+        1            => out.write(read_volatile(ptr)),
+        2 if aligned => (out as *mut u16).write_unaligned(read_volatile(ptr as *const u16)),
+        4 if aligned => (out as *mut u32).write_unaligned(read_volatile(ptr as *const u32)),
+        8 if aligned => (out as *mut u64).write_unaligned(read_volatile(ptr as *const u64)),
+        size         => read_bytes_mapped(ptr, core::slice::from_raw_parts_mut(out, size)),
+    }
+
+    // Any bit-pattern is valid for `Pod` types
+    val.assume_init()
+}
+
+/// Write `val` to `ptr` using volatile accesses, a single one if `T` has a natural access width
+///
+/// # Safety
+/// `ptr` has to be valid for writing `size_of::<T>()` bytes
+unsafe fn write_mapped<T: Pod>(ptr: *mut u8, val: T) {
+    let src = &val as *const T as *const u8;
+    let aligned = (ptr as usize).is_multiple_of(size_of::<T>());
+    match size_of::<T>() {
+        1            => write_volatile(ptr, src.read()),
+        2 if aligned => write_volatile(ptr as *mut u16, (src as *const u16).read_unaligned()),
+        4 if aligned => write_volatile(ptr as *mut u32, (src as *const u32).read_unaligned()),
+        8 if aligned => write_volatile(ptr as *mut u64, (src as *const u64).read_unaligned()),
+        size         => write_bytes_mapped(ptr, core::slice::from_raw_parts(src, size)),
+    }
+}
+
+/// Copy `buf.len()` bytes from `ptr` into `buf`, one volatile byte access at a time
+///
+/// # Safety
+/// `ptr` has to be valid for reading `buf.len()` bytes
+unsafe fn read_bytes_mapped(ptr: *const u8, buf: &mut [u8]) {
+    for (i, byte) in buf.iter_mut().enumerate() {
+        *byte = read_volatile(ptr.add(i));
+    }
+}
+
+/// Copy `buf` to `ptr`, one volatile byte access at a time
+///
+/// # Safety
+/// `ptr` has to be valid for writing `buf.len()` bytes
+unsafe fn write_bytes_mapped(ptr: *mut u8, buf: &[u8]) {
+    for (i, &byte) in buf.iter().enumerate() {
+        write_volatile(ptr.add(i), byte);
+    }
+}
+
+/// Physical memory in `[start, end)` that is identity mapped into the current address space
+#[derive(Debug, Clone, Copy)]
+pub struct IdentityMem {
+    start: u64,
+    end:   u64,
+}
+
+impl IdentityMem {
+    /// Create an accessor for the physical range `[start, end)`
+    ///
+    /// # Safety
+    /// The entire range has to be identity mapped and safe to access
+    pub const unsafe fn new(start: u64, end: u64) -> Self {
+        Self { start, end }
+    }
+}
+
+impl PhysMem for IdentityMem {
+    fn read_bytes(&self, addr: u64, buf: &mut [u8]) -> Result<()> {
+        check_bounds(self.start, self.end, addr, buf.len())?;
+        unsafe { read_bytes_mapped(addr as *const u8, buf) };
+        Ok(())
+    }
+
+    fn write_bytes(&mut self, addr: u64, buf: &[u8]) -> Result<()> {
+        check_bounds(self.start, self.end, addr, buf.len())?;
+        unsafe { write_bytes_mapped(addr as *mut u8, buf) };
+        Ok(())
+    }
+
+    fn read<T: Pod>(&self, addr: u64) -> Result<T> {
+        check_bounds(self.start, self.end, addr, size_of::<T>())?;
+        Ok(unsafe { read_mapped(addr as *const u8) })
+    }
+
+    fn write<T: Pod>(&mut self, addr: u64, val: T) -> Result<()> {
+        check_bounds(self.start, self.end, addr, size_of::<T>())?;
+        unsafe { write_mapped(addr as *mut u8, val) };
+        Ok(())
+    }
+}
+
+/// Physical memory in `[0, size)` that is mapped linearly at virtual address `offset`
+#[derive(Debug, Clone, Copy)]
+pub struct OffsetMem {
+    offset: u64,
+    size:   u64,
+}
+
+impl OffsetMem {
+    /// Create an accessor for physical memory direct-mapped at `offset`
+    ///
+    /// # Safety
+    /// Physical memory `[0, size)` has to be mapped at `[offset, offset + size)` and be safe to
+    /// access
+    pub const unsafe fn new(offset: u64, size: u64) -> Self {
+        Self { offset, size }
+    }
+
+    /// Virtual address of the `len` bytes at physical address `addr`
+    fn vaddr(&self, addr: u64, len: usize) -> Result<u64> {
+        check_bounds(0, self.size, addr, len)?;
+        let vaddr = self.offset.checked_add(addr).ok_or(Error::OutOfBounds { addr, len })?;
+        check_bounds(0, u64::MAX, vaddr, len).map_err(|_| Error::OutOfBounds { addr, len })?;
+        Ok(vaddr)
+    }
+}
+
+impl PhysMem for OffsetMem {
+    fn read_bytes(&self, addr: u64, buf: &mut [u8]) -> Result<()> {
+        let vaddr = self.vaddr(addr, buf.len())?;
+        unsafe { read_bytes_mapped(vaddr as *const u8, buf) };
+        Ok(())
+    }
+
+    fn write_bytes(&mut self, addr: u64, buf: &[u8]) -> Result<()> {
+        let vaddr = self.vaddr(addr, buf.len())?;
+        unsafe { write_bytes_mapped(vaddr as *mut u8, buf) };
+        Ok(())
+    }
+
+    fn read<T: Pod>(&self, addr: u64) -> Result<T> {
+        let vaddr = self.vaddr(addr, size_of::<T>())?;
+        Ok(unsafe { read_mapped(vaddr as *const u8) })
+    }
+
+    fn write<T: Pod>(&mut self, addr: u64, val: T) -> Result<()> {
+        let vaddr = self.vaddr(addr, size_of::<T>())?;
+        unsafe { write_mapped(vaddr as *mut u8, val) };
+        Ok(())
+    }
+}
+
+/// Byte buffer that pretends to be the physical memory at `[base, base + data.len())`
+#[derive(Debug, Clone)]
+pub struct SliceMem<T> {
+    base: u64,
+    data: T,
+}
+
+impl<T: AsRef<[u8]> + AsMut<[u8]>> SliceMem<T> {
+    /// Back physical memory starting at `base` with `data`
+    pub fn new(base: u64, data: T) -> Self {
+        Self { base, data }
+    }
+
+    /// Physical address range `[start, end)` covered by this buffer
+    fn range(&self) -> (u64, u64) {
+        (self.base, self.base + self.data.as_ref().len() as u64)
+    }
+}
+
+impl<T: AsRef<[u8]> + AsMut<[u8]>> PhysMem for SliceMem<T> {
+    fn read_bytes(&self, addr: u64, buf: &mut [u8]) -> Result<()> {
+        let (start, end) = self.range();
+        check_bounds(start, end, addr, buf.len())?;
+        let off = (addr - start) as usize;
+        buf.copy_from_slice(&self.data.as_ref()[off..off + buf.len()]);
+        Ok(())
+    }
+
+    fn write_bytes(&mut self, addr: u64, buf: &[u8]) -> Result<()> {
+        let (start, end) = self.range();
+        check_bounds(start, end, addr, buf.len())?;
+        let off = (addr - start) as usize;
+        self.data.as_mut()[off..off + buf.len()].copy_from_slice(buf);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn slice_mem_bounds() {
+        let mut mem = SliceMem::new(0x1000, vec![0u8; 0x100]);
+        assert_eq!(mem.read::<u64>(0x10f8), Ok(0));
+        assert_eq!(mem.read::<u64>(0x10f9), Err(Error::OutOfBounds { addr: 0x10f9, len: 8 }));
+        assert_eq!(mem.read::<u8>(0xfff), Err(Error::OutOfBounds { addr: 0xfff, len: 1 }));
+        assert_eq!(mem.write::<u32>(0x1100, 1), Err(Error::OutOfBounds { addr: 0x1100, len: 4 }));
+        assert_eq!(mem.read_bytes(u64::MAX, &mut [0u8; 2]),
+                   Err(Error::OutOfBounds { addr: u64::MAX, len: 2 }));
+        assert_eq!(mem.read_bytes(0x1100, &mut []), Ok(()));
+    }
+
+    #[test]
+    fn slice_mem_unaligned() {
+        let mut mem = SliceMem::new(0, [0u8; 16]);
+        mem.write::<u32>(3, 0xdeadbeef).unwrap();
+        assert_eq!(mem.read::<u32>(3), Ok(0xdeadbeef));
+        assert_eq!(mem.read::<[u8; 4]>(3), Ok([0xef, 0xbe, 0xad, 0xde]));
+        assert_eq!(mem.read::<u16>(4), Ok(0xadbe));
+    }
+
+    #[test]
+    fn slice_mem_zero() {
+        let mut mem = SliceMem::new(0, vec![0xffu8; 200]);
+        mem.zero(10, 150).unwrap();
+        assert_eq!(mem.read::<u8>(9), Ok(0xff));
+        assert!((10..160).all(|a| mem.read::<u8>(a) == Ok(0)));
+        assert_eq!(mem.read::<u8>(160), Ok(0xff));
+        assert!(mem.zero(100, 101).is_err());
+    }
+
+    #[test]
+    fn offset_mem() {
+        let mut buf = vec![0u8; 0x40];
+        let mut mem = unsafe { OffsetMem::new(buf.as_mut_ptr() as u64, buf.len() as u64) };
+        mem.write::<u64>(0x21, 0x1122334455667788).unwrap();
+        assert_eq!(mem.read::<u64>(0x21), Ok(0x1122334455667788));
+        assert_eq!(mem.read::<u64>(0x39), Err(Error::OutOfBounds { addr: 0x39, len: 8 }));
+        assert_eq!(buf[0x21], 0x88);
+    }
+
+    #[test]
+    fn offset_mem_overflow() {
+        let mem = unsafe { OffsetMem::new(u64::MAX - 0x10, 0x40) };
+        assert_eq!(mem.read::<u32>(0x20), Err(Error::OutOfBounds { addr: 0x20, len: 4 }));
+        assert_eq!(mem.read::<u64>(0xc), Err(Error::OutOfBounds { addr: 0xc, len: 8 }));
+        assert_eq!(mem.read_bytes(0x8, &mut [0u8; 16]),
+                   Err(Error::OutOfBounds { addr: 0x8, len: 16 }));
+    }
+
+    #[test]
+    fn mapped_access_widths() {
+        let mut buf = vec![0u8; 0x40];
+        let start = buf.as_mut_ptr() as u64;
+        let mut mem = unsafe { IdentityMem::new(start, start + buf.len() as u64) };
+
+        // Aligned, unaligned, and sizes without a single access width
+        mem.write::<u64>(start + 0x8, 0x1122334455667788).unwrap();
+        mem.write::<u32>(start + 0x13, 0xdeadbeef).unwrap();
+        mem.write::<[u8; 3]>(start + 0x20, [1, 2, 3]).unwrap();
+        assert_eq!(mem.read::<u64>(start + 0x8), Ok(0x1122334455667788));
+        assert_eq!(mem.read::<u16>(start + 0x9), Ok(0x6677));
+        assert_eq!(mem.read::<u32>(start + 0x13), Ok(0xdeadbeef));
+        assert_eq!(mem.read::<[u8; 3]>(start + 0x20), Ok([1, 2, 3]));
+        assert_eq!(buf[0x13..0x17], [0xef, 0xbe, 0xad, 0xde]);
+    }
+
+    #[test]
+    fn identity_mem() {
+        let mut buf = vec![0u8; 0x40];
+        let start = buf.as_mut_ptr() as u64;
+        let mut mem = unsafe { IdentityMem::new(start, start + buf.len() as u64) };
+        mem.write::<u16>(start + 0x3e, 0xabcd).unwrap();
+        assert_eq!(mem.read::<u16>(start + 0x3e), Ok(0xabcd));
+        assert!(mem.read::<u16>(start + 0x3f).is_err());
+        assert!(mem.read::<u8>(start - 1).is_err());
+        assert_eq!(buf[0x3f], 0xab);
+    }
+}
@@ -0,0 +1,94 @@
+//! Serial port driver
+//!     - Drives the 16550 UART on COM1, which qemu forwards to stdio with `-serial stdio`
+//!     - Output is polled, interrupts are left disabled
+
+use lazy_static::lazy_static;
+use spin::Mutex;
+use x86::io;
+
+use core::fmt;
+
+/// Io port base of COM1
+const COM1: u16 = 0x3f8;
+
+/// Register offsets from the port base
+const DATA:          u16 = 0;
+const INT_ENABLE:    u16 = 1;
+const FIFO_CONTROL:  u16 = 2;
+const LINE_CONTROL:  u16 = 3;
+const MODEM_CONTROL: u16 = 4;
+const LINE_STATUS:   u16 = 5;
+
+/// The transmit holding register is empty and can take the next byte
+const TRANSMIT_EMPTY: u8 = 1 << 5;
+
+lazy_static! {
+    pub static ref SERIAL: Mutex<SerialPort> = Mutex::new(unsafe { SerialPort::init(COM1) });
+}
+
+#[macro_export]
+macro_rules! serial_print {
+    ($($arg:tt)*) => ($crate::serial::_print(format_args!($($arg)*)));
+}
+
+#[macro_export]
+macro_rules! serial_println {
+    () => ($crate::serial_print!("\n"));
+    ($($arg:tt)*) => ($crate::serial_print!("{}\n", format_args!($($arg)*)));
+}
+
+#[doc(hidden)]
+pub fn _print(args: fmt::Arguments) {
+    use core::fmt::Write;
+    SERIAL.lock().write_fmt(args).unwrap();
+}
+
+/// A 16550 compatible UART
+pub struct SerialPort {
+    base: u16,
+}
+
+impl SerialPort {
+    /// Initialize the UART at io port `base` to 115200 baud, 8 data bits, no parity and 1 stop bit
+    ///
+    /// # Safety
+    /// `base` has to be the io port base of a UART
+    pub unsafe fn init(base: u16) -> Self {
+        io::outb(base + INT_ENABLE, 0x00);
+
+        // Set the divisor latch to 1 for 115200 baud
+        io::outb(base + LINE_CONTROL, 0x80);
+        io::outb(base + DATA, 0x01);
+        io::outb(base + INT_ENABLE, 0x00);
+
+        // 8N1, enable and clear the fifos, assert DTR/RTS
+        io::outb(base + LINE_CONTROL, 0x03);
+        io::outb(base + FIFO_CONTROL, 0xc7);
+        io::outb(base + MODEM_CONTROL, 0x03);
+
+        Self { base }
+    }
+
+    /// Send a single byte, waiting until the UART can take it
+    pub fn write_byte(&mut self, byte: u8) {
+        unsafe {
+            while (io::inb(self.base + LINE_STATUS) & TRANSMIT_EMPTY) == 0 {
+                core::hint::spin_loop();
+            }
+            io::outb(self.base + DATA, byte);
+        }
+    }
+}
+
+impl fmt::Write for SerialPort {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        for byte in s.bytes() {
+            // Terminals expect carriage returns
+            if byte == b'\n' {
+                self.write_byte(b'\r');
+            }
+            self.write_byte(byte);
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,388 @@
+//! Application processor bring-up
+//!     - APs are woken with the INIT-SIPI-SIPI sequence and start executing stage-1 in real mode
+//!       at the trampoline
+//!     - Stage-1 looks up the `CoreInfo` of the AP through the `Mailbox`, enters long mode with its
+//!       page tables and stack and calls `ap_entry` in stage-2, handing it the `BootState`
+//!     - Each AP sets the check-in flag of its `CoreInfo` as soon as stage-1 found it, which is
+//!       how the core that started it knows the startup worked
+//!     - Startup is chained, every AP that reaches stage-2 claims the next processor and starts it.
+//!       The BSP only starts the first one and then watches the progress, taking over if the chain
+//!       stalls for longer than a core can take to start the next one
+//!     - Processors are claimed by moving `Bringup::next` forward with a compare-exchange. A core
+//!       that finds it moved by someone else lost the chain and stops starting processors, so no
+//!       processor is ever started twice
+//!     - https://wiki.osdev.org/Symmetric_Multiprocessing#AP_startup
+
+use crate::{
+    println, config,
+    config::CORE_MEMORY,
+    apic::{self, LocalApic},
+    hpet::HPET,
+    memmap::Owner,
+    mm::{self, PAGE_SIZE},
+    paging::{self, PageTable},
+    physmem::PhysMem,
+    stack::{self, Stack},
+    bootstate::BootState,
+    ipi::Report,
+};
+
+use core::mem::{offset_of, size_of};
+use core::sync::atomic::{AtomicBool, AtomicU8, AtomicU32, AtomicUsize, Ordering};
+use alloc::vec::Vec;
+use x86::{
+    io,
+    cpuid::CpuId,
+};
+
+/// Physical address APs start executing at, the start of stage-1
+pub const TRAMPOLINE: u64 = 0x8000;
+
+/// Physical address of the `Mailbox`, stage-1 hardcodes the same address and field offsets
+pub const AP_MAILBOX: u64 = 0x7e00;
+
+/// Time the processor needs to reset after the INIT IPI
+const INIT_DELAY_NS: u64 = 10_000_000;
+
+/// Time between the two startup IPIs
+const SIPI_DELAY_NS: u64 = 200_000;
+
+/// Longest a core takes to start another one, from claiming it until it checked in or failed
+const START_WINDOW_NS: u64 = INIT_DELAY_NS + 2 * SIPI_DELAY_NS + config::AP_TIMEOUT_MS * 1_000_000;
+
+/// Time without progress after which the BSP takes over the chain. Has to be longer than
+/// `START_WINDOW_NS`, so the BSP never starts a processor while the chain is still busy
+const WATCHDOG_NS: u64 = 2 * START_WINDOW_NS;
+
+/// States of a processor during startup, see `Bringup::states`
+const UNCLAIMED: u8 = 0;
+const STARTING:  u8 = 1;
+const STARTED:   u8 = 2;
+const FAILED:    u8 = 3;
+
+/// Stage-1 looks up the core information from 32-bit code, so it has to live below 4GiB
+const MAILBOX_LIMIT: u64 = 1 << 32;
+
+pub type Result<T> = core::result::Result<T, Error>;
+#[derive(Debug)]
+pub enum Error {
+    /// Failed to send the startup IPIs
+    Apic(apic::Error),
+
+    /// The AP with this APIC id did not check in within `config::AP_TIMEOUT_MS`
+    Timeout(u32),
+
+    /// Core information or page tables ended up above 4GiB, where stage-1 can't reach them
+    NotAddressable(u64),
+}
+
+/// Data stage-1 needs to bring APs into stage-2, at `AP_MAILBOX`
+#[repr(C)]
+struct Mailbox {
+    /// Number of entries in `cores`
+    num_cores: u32,
+
+    /// Function stage-1 calls on the AP's own stack, with pointers to its `CoreInfo` and `state`
+    entry:     u64,
+
+    /// Physical address of the `CoreInfo` array, APs look for the entry with their APIC id
+    cores:     u64,
+
+    /// The `BootState` shared by all cores
+    state:     u64,
+}
+
+/// Parameters of a single core, handed to it when it enters stage-2. Stage-1 reads `apic_id`,
+/// `stack.top` and `page_table` and writes `checkin`, so their offsets can't change
+#[repr(C)]
+#[derive(Debug)]
+pub struct CoreInfo {
+    /// Index of this core, in the order the processors are listed in the MADT
+    pub index:      u32,
+    pub apic_id:    u32,
+    pub stack:      Stack,
+
+    /// Physical memory `[pool_start, pool_end)` set aside for this core
+    pub pool_start: u64,
+    pub pool_end:   u64,
+
+    /// Physical address of the PML4 this core runs with
+    pub page_table: u64,
+
+    /// Set once the core reached stage-2, cleared again when it halts
+    pub(crate) online:  AtomicBool,
+
+    /// Set by stage-1 once the core found this entry after its startup IPIs
+    pub(crate) checkin: AtomicBool,
+
+    /// Command slot other cores fill in, see `ipi`
+    pub(crate) command: AtomicU32,
+
+    /// Answer to the last command, see `ipi`
+    pub(crate) report:  Report,
+
+    /// Generation of the TLB shootdown this core still has to run, 0 if there is none, see `tlb`
+    pub(crate) shootdown: AtomicU32,
+}
+
+// Stage-1 hardcodes these offsets
+const _: () = assert!(offset_of!(CoreInfo, apic_id) == 4);
+const _: () = assert!(offset_of!(CoreInfo, stack) + offset_of!(Stack, top) == 24);
+const _: () = assert!(offset_of!(CoreInfo, page_table) == 48);
+const _: () = assert!(offset_of!(CoreInfo, checkin) == 57);
+const _: () = assert!(size_of::<CoreInfo>() == 136);
+
+const _: () = assert!(CORE_MEMORY.is_multiple_of(PAGE_SIZE),
+                      "CORE_MEMORY needs to be page aligned");
+
+impl CoreInfo {
+    /// Whether this core reached stage-2
+    pub fn is_online(&self) -> bool {
+        self.online.load(Ordering::Acquire)
+    }
+}
+
+/// Progress of the chained startup, shared by all cores through the `BootState`
+pub struct Bringup {
+    /// All cores, including the BSP
+    cores:  &'static [CoreInfo],
+
+    /// APIC id of the BSP, which is already running
+    bsp:    u32,
+
+    /// Index into `cores` of the next processor to start
+    next:   AtomicUsize,
+
+    /// `UNCLAIMED`, `STARTING`, `STARTED` or `FAILED` for every entry in `cores`
+    states: Vec<AtomicU8>,
+
+    /// Number of APs that reached stage-2
+    online: AtomicUsize,
+}
+
+impl Bringup {
+    /// Startup of `cores`, has to be created on the BSP
+    pub fn new(cores: &'static [CoreInfo]) -> Self {
+        Self {
+            cores,
+            bsp:    current_apic_id(),
+            next:   AtomicUsize::new(0),
+            states: cores.iter().map(|_| AtomicU8::new(UNCLAIMED)).collect(),
+            online: AtomicUsize::new(0),
+        }
+    }
+
+    /// Whether every claimed processor checked in or was given up on
+    fn settled(&self) -> bool {
+        self.states.iter().all(|s| s.load(Ordering::Acquire) != STARTING)
+    }
+
+    /// Whether every processor that checked in also reached stage-2
+    fn all_online(&self) -> bool {
+        self.cores.iter().zip(&self.states)
+            .all(|(c, s)| s.load(Ordering::Acquire) != STARTED || c.is_online())
+    }
+}
+
+/// The mailbox shared with stage-1
+fn mailbox() -> &'static mut Mailbox {
+    unsafe { &mut *(AP_MAILBOX as *mut Mailbox) }
+}
+
+/// APIC id of the core this is running on. Prefers the full x2APIC id over the 8-bit initial
+/// APIC id, the same way stage-1 does
+pub fn current_apic_id() -> u32 {
+    let cpuid = CpuId::new();
+    if let Some(level) = cpuid.get_extended_topology_info().and_then(|mut t| t.next()) {
+        return level.x2apic_id();
+    }
+    cpuid.get_feature_info().map_or(0, |f| f.initial_local_apic_id() as u32)
+}
+
+/// Busy wait for `ns` nanoseconds. Uses the HPET if it was set up, otherwise falls back to writes
+/// to the POST port, each of which takes roughly a microsecond
+fn delay_ns(ns: u64) {
+    match HPET.r#try() {
+        Some(hpet) => hpet.sleep_ns(ns),
+        None => {
+            for _ in 0..ns.div_ceil(1000) {
+                unsafe { io::outb(0x80, 0); }
+            }
+        }
+    }
+}
+
+/// Poll `done` until it returns true or `ns` nanoseconds passed. Returns whether `done` succeeded
+pub fn wait_for(ns: u64, mut done: impl FnMut() -> bool) -> bool {
+    const STEP_NS: u64 = 10_000;
+
+    let mut waited = 0;
+    while waited < ns {
+        if done() {
+            return true;
+        }
+        delay_ns(STEP_NS);
+        waited += STEP_NS;
+    }
+    done()
+}
+
+/// Allocate a stack and a memory pool of `CORE_MEMORY` bytes for each core in `apic_ids`. All
+/// cores run with the page tables of `table` for now
+///
+/// # Safety
+/// Same as `Stack::alloc`
+pub unsafe fn alloc_cores<M: PhysMem>(apic_ids: &[u32], table: &mut PageTable<M>)
+        -> paging::Result<Vec<CoreInfo>> {
+    let stacks = stack::alloc_stacks(apic_ids.len(), table)?;
+    let mut cores = Vec::with_capacity(apic_ids.len());
+    for (index, (&apic_id, stack)) in apic_ids.iter().zip(stacks).enumerate() {
+        let pool_start = mm::alloc_frames(CORE_MEMORY / PAGE_SIZE, Owner::CoreMemory)
+            .ok_or(paging::Error::OutOfMemory)?;
+        cores.push(CoreInfo {
+            index:      index as u32,
+            apic_id,
+            stack,
+            pool_start,
+            pool_end:   pool_start + CORE_MEMORY,
+            page_table: table.root(),
+            online:     AtomicBool::new(false),
+            checkin:    AtomicBool::new(false),
+            command:    AtomicU32::new(0),
+            report:     Report::default(),
+            shootdown:  AtomicU32::new(0),
+        });
+    }
+    Ok(cores)
+}
+
+/// Start the AP of `core` and wait for it to check in
+///
+/// # Safety
+/// Same as `LocalApic::current`, and stage-1 has to be in place at `TRAMPOLINE`
+pub unsafe fn start_ap(core: &CoreInfo) -> Result<()> {
+    let mut apic = LocalApic::current();
+    let apic_id = core.apic_id;
+    core.checkin.store(false, Ordering::Release);
+
+    // Reset the processor, then point it at the trampoline. The second SIPI is required by the
+    // spec in case the first one was lost, an AP that is already running ignores it
+    apic.init_ipi(apic_id).map_err(Error::Apic)?;
+    delay_ns(INIT_DELAY_NS);
+    for _ in 0..2 {
+        apic.startup_ipi(apic_id, (TRAMPOLINE >> 12) as u8).map_err(Error::Apic)?;
+        delay_ns(SIPI_DELAY_NS);
+    }
+
+    if !wait_for(config::AP_TIMEOUT_MS * 1_000_000, || core.checkin.load(Ordering::Acquire)) {
+        return Err(Error::Timeout(apic_id));
+    }
+    Ok(())
+}
+
+/// Claim the processor at index `idx` of `cores` and start it. Processors that fail to start are
+/// skipped, so one broken core does not end the chain. Returns without starting anything once
+/// another core claimed `idx`
+///
+/// # Safety
+/// Same as `start_ap`
+unsafe fn launch_from(bringup: &Bringup, mut idx: usize) {
+    while let Some(core) = bringup.cores.get(idx) {
+        let claimed = bringup.next.compare_exchange(idx, idx + 1, Ordering::AcqRel,
+                                                    Ordering::Acquire).is_ok();
+        if !claimed {
+            return;
+        }
+        let state = &bringup.states[idx];
+        idx += 1;
+        if core.apic_id == bringup.bsp {
+            continue;
+        }
+
+        state.store(STARTING, Ordering::Release);
+        match start_ap(core) {
+            Ok(()) => {
+                let _ = state.compare_exchange(STARTING, STARTED, Ordering::AcqRel,
+                                               Ordering::Acquire);
+                return;
+            }
+            Err(v) => {
+                println!("Failed to start core: {:?}", v);
+                let _ = state.compare_exchange(STARTING, FAILED, Ordering::AcqRel,
+                                               Ordering::Acquire);
+            }
+        }
+    }
+}
+
+/// Called by every AP once it reached stage-2. Marks the AP as online and continues the chain
+///
+/// # Safety
+/// Same as `start_ap`
+pub unsafe fn ap_online(bringup: &Bringup, core: &CoreInfo) {
+    let idx = core.index as usize;
+    bringup.states[idx].store(STARTED, Ordering::Release);
+    if !core.online.swap(true, Ordering::AcqRel) {
+        bringup.online.fetch_add(1, Ordering::AcqRel);
+    }
+    launch_from(bringup, idx + 1);
+}
+
+/// Start all cores of `state` except for the BSP and wait for them to reach stage-2, where they
+/// call `entry`. Every core gets `config::AP_TIMEOUT_MS` to check in, if the chain stalls for
+/// `WATCHDOG_NS` the BSP starts the next processor itself. Returns once every processor is online
+/// or was given up on, with the number of APs that came online
+///
+/// # Safety
+/// Same as `start_ap`, has to be called once by the BSP. The page tables of all cores have to
+/// identity map stage-2 and `state`
+pub unsafe fn start_aps(state: &'static BootState,
+                        entry: extern "C" fn(&'static CoreInfo, &'static BootState) -> !)
+        -> Result<usize> {
+    let cores = state.cores;
+    let addr  = cores.as_ptr() as u64;
+    if addr + core::mem::size_of_val(cores) as u64 > MAILBOX_LIMIT {
+        return Err(Error::NotAddressable(addr));
+    }
+    if let Some(core) = cores.iter().find(|c| c.page_table >= MAILBOX_LIMIT) {
+        return Err(Error::NotAddressable(core.page_table));
+    }
+
+    let mailbox = mailbox();
+    mailbox.num_cores = cores.len() as u32;
+    mailbox.entry     = entry as usize as u64;
+    mailbox.cores     = addr;
+    mailbox.state     = state as *const BootState as u64;
+
+    let bringup = &state.bringup;
+    if let Some(core) = cores.iter().find(|c| c.apic_id == bringup.bsp) {
+        core.online.store(true, Ordering::Release);
+    }
+
+    launch_from(bringup, 0);
+    loop {
+        let next = bringup.next.load(Ordering::Acquire);
+        if next >= cores.len() {
+            break;
+        }
+
+        // Whoever owns the chain did not claim another processor in time, it is stuck. The
+        // processor it was starting is given up on
+        if !wait_for(WATCHDOG_NS, || bringup.next.load(Ordering::Acquire) != next) {
+            if let Some(state) = next.checked_sub(1).map(|idx| &bringup.states[idx]) {
+                let _ = state.compare_exchange(STARTING, FAILED, Ordering::AcqRel,
+                                               Ordering::Acquire);
+            }
+            launch_from(bringup, next);
+        }
+    }
+
+    // The last processors may still be starting. Anything that did not check in within a watchdog
+    // period never will
+    wait_for(WATCHDOG_NS, || bringup.settled());
+    for state in &bringup.states {
+        let _ = state.compare_exchange(STARTING, FAILED, Ordering::AcqRel, Ordering::Acquire);
+    }
+    wait_for(WATCHDOG_NS, || bringup.all_online());
+    Ok(bringup.online.load(Ordering::Acquire))
+}
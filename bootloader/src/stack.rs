@@ -0,0 +1,73 @@
+//! Per-core stacks
+//!     - Every core gets its own stack allocated from the frame allocator
+//!     - The page directly below each stack is left unmapped, so an overflow faults instead of
+//!       silently corrupting whatever lies below it
+
+use crate::{
+    config::STACK_SIZE,
+    mm::{self, PAGE_SIZE},
+    memmap::Owner,
+    paging::{self, PageTable},
+    physmem::PhysMem,
+};
+
+use alloc::vec::Vec;
+
+const _: () = assert!(STACK_SIZE.is_multiple_of(PAGE_SIZE), "STACK_SIZE needs to be page aligned");
+
+/// Stack of a single core. Stage-1 reads `top` when starting APs, see `smp::CoreInfo`
+#[repr(C)]
+#[derive(Debug, Default, Copy, Clone)]
+pub struct Stack {
+    /// Address of the unmapped guard page directly below the stack
+    pub guard: u64,
+
+    /// Lowest usable address of the stack
+    pub bottom: u64,
+
+    /// Initial stack pointer, the stack grows down from here towards `bottom`
+    pub top: u64,
+}
+
+impl Stack {
+    /// Allocate a `STACK_SIZE` stack and unmap the guard page below it in `table`
+    ///
+    /// # Safety
+    /// Same as `PageTable::unmap`
+    pub unsafe fn alloc<M: PhysMem>(table: &mut PageTable<M>) -> paging::Result<Self> {
+        let guard = mm::alloc_frames(STACK_SIZE / PAGE_SIZE + 1, Owner::Stacks)
+            .ok_or(paging::Error::OutOfMemory)?;
+        table.unmap(guard)?;
+
+        Ok(Self {
+            guard,
+            bottom: guard + PAGE_SIZE,
+            top:    guard + PAGE_SIZE + STACK_SIZE,
+        })
+    }
+
+    /// Switch execution over to this stack and call `f(arg)` on it. The current stack is abandoned
+    ///
+    /// # Safety
+    /// The stack has to be mapped and not be in use by any other core
+    pub unsafe fn switch_to<T>(&self, f: extern "C" fn(&'static T) -> !, arg: &'static T) -> ! {
+        core::arch::asm!(
+            "mov rsp, {stack}",
+            "xor ebp, ebp",
+            "call {f}",
+            stack = in(reg) self.top,
+            f     = in(reg) f,
+            in("rdi") arg,
+            options(noreturn),
+        );
+    }
+}
+
+/// Allocate a stack for each of `num_cores` cores
+///
+/// # Safety
+/// Same as `Stack::alloc`
+pub unsafe fn alloc_stacks<M: PhysMem>(num_cores: usize, table: &mut PageTable<M>)
+        -> paging::Result<Vec<Stack>> {
+    (0..num_cores).map(|_| Stack::alloc(table)).collect()
+}
@@ -2,7 +2,7 @@
 [org 0x7c00]
 
 ; Stage 0 bootloader. Performs some checks to verify that the system is 
-; compatible before loading the stage1 bootloader to 0x1000 and jumping to it
+; compatible before loading the stage1 bootloader to 0x8000 and jumping to it
 
 ; Contains information such as the offset and size of the stage1 bootloader
 ; that is used when loading it from disk
@@ -110,12 +110,12 @@ long_mode_err_len: equ $-long_mode_err_msg
 load_stage1_err_msg: db "Error reading stage1 bootloader from disk"
 load_stage1_err_len: equ $-load_stage1_err_msg
 
-; Initialize the structure passed to BIOS 0x13 to read 2 sectors to physical
-; address 0x1000
+; Initialize the structure passed to BIOS 0x13 to read all 5 sectors of stage1
+; to physical address 0x8000
 load_stage1_packet: istruc disk_address_packet_type
     at disk_address_packet_type.size, db        0x10
     at disk_address_packet_type.zero, db        0x0
-    at disk_address_packet_type.num_sectors, dw 0x4
+    at disk_address_packet_type.num_sectors, dw 0x5
     at disk_address_packet_type.offset, dw      0x8000
     at disk_address_packet_type.segment, dw     0x0
     at disk_address_packet_type.address_lo, dd  0x1
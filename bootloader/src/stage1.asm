@@ -1,7 +1,32 @@
 [bits 16]
 [org 0x8000]
 
-; Stage 1 bootloader. 512 * 4 bytes of space. 
+; Mailbox shared with stage-2 to start APs, see `smp::Mailbox`
+AP_MAILBOX        equ 0x7e00
+MAILBOX_NUM_CORES equ AP_MAILBOX + 0
+MAILBOX_ENTRY     equ AP_MAILBOX + 8
+MAILBOX_CORES     equ AP_MAILBOX + 16
+MAILBOX_STATE     equ AP_MAILBOX + 24
+
+; Field offsets of `smp::CoreInfo`
+CORE_APIC_ID    equ 4
+CORE_STACK_TOP  equ 24
+CORE_PAGE_TABLE equ 48
+CORE_CHECKIN    equ 57
+CORE_INFO_SIZE  equ 136
+
+; Stage-2 is linked to run at STAGE2_BASE and may use memory up to STAGE2_END. Its raw image is read
+; from disk to STAGE2_IMAGE first, STAGE2_SECTORS has to match the host tool that builds the image
+STAGE2_BASE    equ 0x10000
+STAGE2_END     equ 0x40000
+STAGE2_IMAGE   equ 0x40000
+STAGE2_SECTORS equ 384
+
+; Bioses are only required to read 127 sectors at once, so the image is read in chunks of this many
+; sectors. STAGE2_SECTORS has to be a multiple of it
+STAGE2_CHUNK   equ 64
+
+; Stage 1 bootloader. 512 * 5 bytes of space. 
 ; Loads the stage2 bootloader (rust part), retrieves the memory map, enables the a0 line and
 ; enters protected & long mode
 
@@ -23,7 +48,8 @@ enable_a20:
     or al, 2
     out 0x92, al
 
-; Load the stage2 bootloader from disk to memory (rust portion of bootloader)
+; Load the stage2 bootloader from disk to memory (rust portion of bootloader). The image does not
+; fit between stage-1 and the address stage-2 runs at, so it is read to STAGE2_IMAGE instead
 load_stage2:
     mov si, load_stage2_packet
     mov dl, [drive_id]
@@ -31,6 +57,11 @@ load_stage2:
     int 0x13
     jc read_error
 
+    add word [load_stage2_packet + disk_address_packet_type.segment], (STAGE2_CHUNK * 512) >> 4
+    add dword [load_stage2_packet + disk_address_packet_type.address_lo], STAGE2_CHUNK
+    sub word [stage2_sectors_left], STAGE2_CHUNK
+    jnz load_stage2
+
 ; Retrieve the memory layout to determine what space we are free to use. This structure is then
 ; pushed onto the stack before jumping into rust code, so the rust portion of the bootloader can
 ; make use of this information to setup the initial memory manager
@@ -165,21 +196,21 @@ pm_entry:
 lm_entry:
      mov rsp, 0x7c00
  
-; The rust portion of the bootloader was simply appended to stage1. This means
-; that it still needs to be written to the correct memory locations. It comes
-; alongside some metadata describing the amount of sections and each sections
-; vaddr/size
+; The rust portion of the bootloader was simply appended to stage1 and read to
+; STAGE2_IMAGE. This means that it still needs to be written to the correct
+; memory locations. It comes alongside some metadata describing the amount of
+; sections and each sections vaddr/size
 run_stage2:
-	; Zero out entire range where the bootloader is loaded [0x10000, 0x20000)
+	; Zero out entire range where the bootloader is loaded [STAGE2_BASE, STAGE2_END)
     ; Ram is not necessarily 0 initialized, so this makes sure that memory is
     ; not already pre-initialized, which could cause issues
-	mov edi, 0x10000
-	mov ecx, 0x20000 - 0x10000
+	mov edi, STAGE2_BASE
+	mov ecx, STAGE2_END - STAGE2_BASE
 	xor eax, eax
 	rep stosb
 
-    mov eax, [rust_entry]       ; Num_sections
-    lea edx, [rust_entry + 4]   ; Initialize edx to start of first struct
+    mov eax, [STAGE2_IMAGE]       ; Num_sections
+    lea edx, [STAGE2_IMAGE + 4]   ; Initialize edx to start of first struct
 
 .loop:
     test eax, eax
@@ -205,7 +236,7 @@ run_stage2:
     mov rdi, E820Entries
 
     ; Call Stage-2 entry function
-    call 0x10000
+    call STAGE2_BASE
 
 l_end:
     hlt
@@ -224,17 +255,82 @@ ap_entry:
 
 [bits 32]
 ap_pm_entry:
-    mov byte[0xb8000], 'H'
-    mov byte[0xb8001], 0xa
-    mov byte[0xb8002], 'e'
-    mov byte[0xb8003], 0xa
-    mov byte[0xb8004], 'l'
-    mov byte[0xb8005], 0xa
-    mov byte[0xb8006], 'l'
-    mov byte[0xb8007], 0xa
-    mov byte[0xb8008], 'o'
-    mov byte[0xb8009], 0xa
+    mov ax, 0x10
+    mov ds, ax
+    mov es, ax
+    mov fs, ax
+    mov gs, ax
+    mov ss, ax
+
+    ; Find the core information stage-2 prepared for us by our APIC id. Use the full x2APIC id
+    ; from leaf 0xb if the processor has it, ids above 255 don't fit into the initial APIC id
+    xor eax, eax
+    cpuid
+    cmp eax, 0xb
+    jb .initial_id
+    mov eax, 0xb
+    xor ecx, ecx
+    cpuid
+    test ebx, ebx
+    jz .initial_id
+    mov ebx, edx
+    jmp .have_id
+.initial_id:
+    mov eax, 1
+    cpuid
+    shr ebx, 24
+.have_id:
+    mov esi, [MAILBOX_CORES]
+    mov ecx, [MAILBOX_NUM_CORES]
+.find_core:
+    test ecx, ecx
+    jz ap_halt
+    cmp [esi + CORE_APIC_ID], ebx
+    je .found_core
+    add esi, CORE_INFO_SIZE
+    dec ecx
+    jmp .find_core
+
+; Let whoever started us know we are up, then enter long mode the same way as the BSP, but with
+; the page tables of this core
+.found_core:
+    mov byte [esi + CORE_CHECKIN], 1
+    lgdt [gdt64]
+
+    mov eax, cr4
+    or eax, (1 << 5)
+    mov cr4, eax
+    mov eax, [esi + CORE_PAGE_TABLE]
+    mov cr3, eax
+
+    mov ecx, 0xc0000080
+    rdmsr
+    or eax, (1 << 8)
+    wrmsr
+
+    mov eax, cr0
+    or eax, (1 << 31)
+    mov cr0, eax
+
+    jmp 0x0008:ap_lm_entry
+
+; Nothing to do for this core
+ap_halt:
+    cli
     hlt
+    jmp ap_halt
+
+[bits 64]
+
+; Switch to the stack of this core and call `ap_entry(&CoreInfo, &BootState)` in stage-2
+ap_lm_entry:
+    mov esi, esi
+    mov rsp, [rsi + CORE_STACK_TOP]
+    xor ebp, ebp
+    mov rdi, rsi
+    mov rsi, [MAILBOX_STATE]
+    call [MAILBOX_ENTRY]
+    jmp ap_halt
 
 ; Structures
 ; ------------------------------------------------------------------------------
@@ -262,13 +358,16 @@ endstruc
 load_stage2_packet: istruc disk_address_packet_type
     at disk_address_packet_type.size, db        0x10
     at disk_address_packet_type.zero, db        0
-    at disk_address_packet_type.num_sectors, dw 60
-    at disk_address_packet_type.offset, dw      0x8800
-    at disk_address_packet_type.segment, dw     0
-    at disk_address_packet_type.address_lo, dd  0x5
+    at disk_address_packet_type.num_sectors, dw STAGE2_CHUNK
+    at disk_address_packet_type.offset, dw      0
+    at disk_address_packet_type.segment, dw     STAGE2_IMAGE >> 4
+    at disk_address_packet_type.address_lo, dd  0x6
     at disk_address_packet_type.address_hi, dd  0x0
 iend
 
+; Sectors of the stage2 image that still have to be read
+stage2_sectors_left: dw STAGE2_SECTORS
+
 E820Entries times (20 * 33) db 0
 
 ; 32-bit protected mode gdt
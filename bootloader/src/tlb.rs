@@ -0,0 +1,179 @@
+//! Cross-core TLB shootdown
+//!     - Changing a mapping other cores may have cached requires them to invalidate it as well
+//!     - The initiator publishes the request, raises `SHOOTDOWN_VECTOR` on every other online core
+//!       that runs with the same page tables, and waits until all of them acknowledged it
+//!     - Only one shootdown runs at a time. Targets have to take interrupts to acknowledge (eg.
+//!       be parked), a core that runs with interrupts disabled makes the shootdown time out.
+//!       Initiators waiting for their turn serve requests sent to them in the meantime
+//!     - Every target gets the generation of the request in its `CoreInfo`, and only acknowledges
+//!       that generation. An IPI that arrives after its request timed out does not count towards
+//!       the next one
+
+use crate::{
+    apic, percpu,
+    mm::PAGE_SIZE,
+    interrupts::SHOOTDOWN_VECTOR,
+    smp::{self, CoreInfo},
+};
+
+use core::sync::atomic::{AtomicU64, Ordering};
+use spin::{Mutex, Once};
+use x86::controlregs;
+
+/// Cores that take part in shootdowns, registered once they are about to be started
+static CORES: Once<&'static [CoreInfo]> = Once::new();
+
+/// Serializes initiators, the request itself lives in the atomics below so handlers never lock
+static SHOOTDOWN: Mutex<()> = Mutex::new(());
+
+/// Current request, see `Flush::encode`
+static REQUEST_START: AtomicU64 = AtomicU64::new(0);
+static REQUEST_PAGES: AtomicU64 = AtomicU64::new(0);
+
+/// Generation of the current request in the upper 32 bits and the number of cores that still have
+/// to acknowledge it in the lower 32 bits. Generations are never 0, that marks an empty slot
+static PENDING: AtomicU64 = AtomicU64::new(0);
+
+/// Above this many pages the whole TLB is flushed instead of invalidating every page
+const MAX_INVLPG: u64 = 32;
+
+/// Time targets get to acknowledge a shootdown
+const SHOOTDOWN_TIMEOUT_NS: u64 = 10_000_000;
+
+pub type Result<T> = core::result::Result<T, Error>;
+#[derive(Debug)]
+pub enum Error {
+    /// This many cores did not acknowledge the shootdown in time
+    Timeout { pending: u32 },
+
+    /// Failed to send the IPI to a core
+    Apic(apic::Error),
+}
+
+/// Translations to invalidate
+#[derive(Debug, Clone, Copy)]
+pub enum Flush {
+    /// The 4KiB pages `[start, start + pages * 4KiB)`
+    Range { start: u64, pages: u64 },
+
+    /// Everything except global pages, by reloading cr3
+    All,
+}
+
+impl Flush {
+    /// Invalidate on this core
+    unsafe fn local(self) {
+        match self {
+            Flush::Range { start, pages } if pages <= MAX_INVLPG => {
+                for page in 0..pages {
+                    x86::tlb::flush((start + page * PAGE_SIZE) as usize);
+                }
+            }
+            _ => controlregs::cr3_write(controlregs::cr3()),
+        }
+    }
+
+    /// Publish the request to the handlers, a page count of `u64::MAX` means `All`
+    fn encode(self) {
+        let (start, pages) = match self {
+            Flush::Range { start, pages } => (start, pages),
+            Flush::All => (0, u64::MAX),
+        };
+        REQUEST_START.store(start, Ordering::Relaxed);
+        REQUEST_PAGES.store(pages, Ordering::Relaxed);
+    }
+
+    /// The request published by `encode`
+    fn decode() -> Self {
+        match REQUEST_PAGES.load(Ordering::Relaxed) {
+            u64::MAX => Flush::All,
+            pages => Flush::Range { start: REQUEST_START.load(Ordering::Relaxed), pages },
+        }
+    }
+}
+
+/// Take part in shootdowns with `cores` from now on, before this they only flush locally
+pub fn init(cores: &'static [CoreInfo]) {
+    CORES.call_once(|| cores);
+}
+
+/// Invalidate `flush` on this core and on every other online core running with the page table
+/// at `root`, waiting until all of them are done
+///
+/// # Safety
+/// Same as `LocalApic::current`, and the IDT has to be loaded on all cores taking part
+pub unsafe fn shootdown(root: u64, flush: Flush) -> Result<()> {
+    flush.local();
+
+    let Some(cores) = CORES.r#try() else { return Ok(()) };
+    let this = smp::current_apic_id();
+    let targets = || cores.iter()
+        .filter(move |c| c.is_online() && c.apic_id != this && c.page_table == root);
+    if targets().next().is_none() {
+        return Ok(());
+    }
+
+    // The current initiator might be waiting for this core, which may not take interrupts here
+    let own = percpu::current().map(|p| p.core);
+    let _guard = loop {
+        if let Some(guard) = SHOOTDOWN.try_lock() {
+            break guard;
+        }
+        if let Some(own) = own {
+            serve(own);
+        }
+        core::hint::spin_loop();
+    };
+
+    flush.encode();
+    let generation = match ((PENDING.load(Ordering::Acquire) >> 32) as u32).wrapping_add(1) {
+        0 => 1,
+        v => v,
+    };
+    PENDING.store((generation as u64) << 32 | targets().count() as u64, Ordering::Release);
+
+    let mut result = Ok(());
+    for core in targets() {
+        core.shootdown.store(generation, Ordering::Release);
+        if let Err(v) = apic::send_ipi(core.apic_id, SHOOTDOWN_VECTOR) {
+            // This core will never acknowledge
+            PENDING.fetch_sub(1, Ordering::AcqRel);
+            result = Err(Error::Apic(v));
+        }
+    }
+
+    let done = smp::wait_for(SHOOTDOWN_TIMEOUT_NS,
+                             || PENDING.load(Ordering::Acquire) as u32 == 0);
+    if !done {
+        return Err(Error::Timeout { pending: PENDING.load(Ordering::Acquire) as u32 });
+    }
+    result
+}
+
+/// Serve the request sent to this core, called from the `SHOOTDOWN_VECTOR` handler
+pub(crate) fn handle() {
+    if let Some(percpu) = percpu::current() {
+        serve(percpu.core);
+    }
+}
+
+/// Run the request `core` was sent and acknowledge it. Does nothing if it was already served
+fn serve(core: &CoreInfo) {
+    let generation = core.shootdown.swap(0, Ordering::AcqRel);
+    if generation == 0 {
+        return;
+    }
+
+    // The request of an earlier generation was already replaced, flush everything to be sure its
+    // pages are gone as well
+    let pending = PENDING.load(Ordering::Acquire);
+    let flush = match (pending >> 32) as u32 == generation {
+        true  => Flush::decode(),
+        false => Flush::All,
+    };
+    unsafe { flush.local(); }
+
+    let _ = PENDING.fetch_update(Ordering::AcqRel, Ordering::Acquire, |pending| {
+        ((pending >> 32) as u32 == generation && pending as u32 != 0).then(|| pending - 1)
+    });
+}
@@ -0,0 +1,22 @@
+//! Fuzz the ACPI parser. The input is placed in the BIOS read-only area the parser scans for the
+//! RSDP, and the rest of the low 1MiB is zeroed out. Table pointers can point anywhere into the
+//! input
+
+#![no_main]
+
+use bootloader::{acpi::ParsedACPI, physmem::SliceMem};
+use libfuzzer_sys::fuzz_target;
+
+/// Physical address the input is placed at
+const INPUT_BASE: usize = 0xe0000;
+
+/// Always back at least the whole range the parser scans for the RSDP
+const MIN_MEM_SIZE: usize = 0x100000 + 0x100;
+
+fuzz_target!(|data: &[u8]| {
+    let mut memory = vec![0u8; core::cmp::max(INPUT_BASE + data.len(), MIN_MEM_SIZE)];
+    memory[INPUT_BASE..INPUT_BASE + data.len()].copy_from_slice(data);
+
+    let mem = SliceMem::new(0, memory);
+    let _ = ParsedACPI::parse(&mem, None);
+});
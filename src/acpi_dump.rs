@@ -0,0 +1,152 @@
+//! Reassemble acpi tables that stage-2 streamed over serial (see `bootloader/src/acpi/dump.rs`)
+//! into one `<signature>_<address>.dat` file per table
+
+use std::path::Path;
+
+/// A table that is currently being reassembled
+struct Table {
+    name: String,
+    addr: u64,
+    data: Vec<u8>,
+    len:  usize,
+}
+
+/// Hash used by stage-2 to verify tables arrived intact, 32-bit FNV-1a
+fn fnv1a(data: &[u8]) -> u32 {
+    data.iter().fold(0x811c_9dc5, |hash, &byte| (hash ^ byte as u32).wrapping_mul(0x0100_0193))
+}
+
+/// Turn the signature bytes into a file name, replacing anything that is not alphanumeric
+fn table_name(signature: &str) -> Option<String> {
+    let raw = u32::from_str_radix(signature, 16).ok()?.to_be_bytes();
+    Some(raw.iter().map(|&b| if b.is_ascii_alphanumeric() { b as char } else { '_' }).collect())
+}
+
+/// Extract all tables from the serial log at `log` and write them to `out_dir`. Returns the number
+/// of tables written
+pub fn extract(log: &str, out_dir: &str) -> Result<usize, String> {
+    let log = std::fs::read_to_string(log).map_err(|e| format!("Failed to read {}: {}", log, e))?;
+    std::fs::create_dir_all(out_dir)
+        .map_err(|e| format!("Failed to create {}: {}", out_dir, e))?;
+
+    let mut cur: Option<Table> = None;
+    let mut written = 0;
+
+    for (idx, line) in log.lines().enumerate() {
+        // Serial output may contain carriage returns, and other output may precede the frame
+        let line = match line.find("@ACPI ") {
+            Some(start) => line[start + 6..].trim_end(),
+            None => continue,
+        };
+        let mut parts = line.split_whitespace();
+        let err = |msg: &str| format!("line {}: {}", idx + 1, msg);
+
+        match parts.next() {
+            // A new boot in the same log, drop whatever was cut off
+            Some("BEGIN") => cur = None,
+            Some("TABLE") => {
+                let name = parts.next().and_then(table_name).ok_or_else(|| err("bad signature"))?;
+                let addr = parts.next().and_then(|a| u64::from_str_radix(a, 16).ok())
+                    .ok_or_else(|| err("bad address"))?;
+                let len = parts.next().and_then(|l| usize::from_str_radix(l, 16).ok())
+                    .ok_or_else(|| err("bad length"))?;
+                cur = Some(Table { name, addr, data: Vec::with_capacity(len), len });
+            }
+            Some("DATA") => {
+                let table = cur.as_mut().ok_or_else(|| err("data outside of a table"))?;
+                let hex = parts.next().unwrap_or("");
+                if hex.len() % 2 != 0 {
+                    return Err(err("odd number of hex digits"));
+                }
+                for i in (0..hex.len()).step_by(2) {
+                    let byte = u8::from_str_radix(&hex[i..i + 2], 16)
+                        .map_err(|_| err("bad hex digits"))?;
+                    table.data.push(byte);
+                }
+            }
+            Some("END") => {
+                let table = cur.take().ok_or_else(|| err("end outside of a table"))?;
+                let hash = parts.next().and_then(|h| u32::from_str_radix(h, 16).ok())
+                    .ok_or_else(|| err("bad hash"))?;
+                if table.data.len() != table.len || fnv1a(&table.data) != hash {
+                    return Err(err(&format!("table {} is corrupted", table.name)));
+                }
+
+                // The address keeps tables like the SSDT that show up multiple times apart, and
+                // allows loading the tables back into memory where the firmware put them
+                let file = format!("{}_{:x}.dat", table.name, table.addr);
+                std::fs::write(Path::new(out_dir).join(&file), &table.data)
+                    .map_err(|e| format!("Failed to write {}: {}", file, e))?;
+                println!("{:<24} {:#x} bytes", file, table.data.len());
+                written += 1;
+            }
+            _ => {}
+        }
+    }
+    Ok(written)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bootloader::{acpi::{self, ParsedACPI}, physmem::{PhysMem, SliceMem}};
+
+    /// Tables captured from a firecracker guest, named the way `extract` names them
+    const FIXTURES: &str = concat!(env!("CARGO_MANIFEST_DIR"),
+                                   "/bootloader/src/acpi/testdata/firecracker");
+
+    /// Load every `<signature>_<address>.dat` file in `dir` into 1MiB of memory
+    fn load(dir: &Path) -> SliceMem<Vec<u8>> {
+        let mut mem = SliceMem::new(0, vec![0u8; 1024 * 1024]);
+        for entry in std::fs::read_dir(dir).unwrap() {
+            let path = entry.unwrap().path();
+            let stem = path.file_stem().unwrap().to_str().unwrap();
+            let addr = u64::from_str_radix(stem.split_once('_').unwrap().1, 16).unwrap();
+            mem.write_bytes(addr, &std::fs::read(&path).unwrap()).unwrap();
+        }
+        mem
+    }
+
+    #[test]
+    fn round_trip() {
+        let mem  = load(Path::new(FIXTURES));
+        let acpi = ParsedACPI::parse(&mem, None).unwrap();
+
+        // Other output and carriage returns around the frame, like in a real serial log
+        let mut log = String::from("Entered rust part of bootloader\n");
+        acpi::dump::dump(&mem, &acpi, &mut log).unwrap();
+        let log = log.replace('\n', "\r\n") + "Done with stage2\n";
+
+        let dir = std::env::temp_dir().join(format!("vfuzz-acpi-dump-{}", std::process::id()));
+        let log_file = dir.join("serial.log");
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(&log_file, log).unwrap();
+        let tables = dir.join("tables");
+        let written = extract(log_file.to_str().unwrap(), tables.to_str().unwrap()).unwrap();
+
+        // Every file comes back under the same name, byte for byte
+        assert_eq!(written, std::fs::read_dir(FIXTURES).unwrap().count());
+        for entry in std::fs::read_dir(FIXTURES).unwrap() {
+            let path = entry.unwrap().path();
+            let copy = std::fs::read(tables.join(path.file_name().unwrap())).unwrap();
+            assert_eq!(copy, std::fs::read(&path).unwrap(), "{:?}", path);
+        }
+
+        // And parses to the same result
+        let reparsed = ParsedACPI::parse(&load(&tables), None).unwrap();
+        assert_eq!(reparsed.ranges().collect::<Vec<_>>(), acpi.ranges().collect::<Vec<_>>());
+        assert_eq!(reparsed.madt.processors.len(), acpi.madt.processors.len());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn corrupted_table() {
+        let log = "@ACPI BEGIN\n@ACPI TABLE 41504943 a0d97 2\n@ACPI DATA 4151\n@ACPI END 0\n";
+        let dir = std::env::temp_dir().join(format!("vfuzz-acpi-bad-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join("serial.log"), log).unwrap();
+        let res = extract(dir.join("serial.log").to_str().unwrap(), dir.to_str().unwrap());
+        assert_eq!(res, Err("line 4: table APIC is corrupted".to_string()));
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -1,4 +1,11 @@
-use elfparser;
+mod acpi_dump;
+
+/// Number of sectors stage-1 reads the flattened stage-2 image from, has to match
+/// `STAGE2_SECTORS` in `bootloader/src/stage1.asm`
+const STAGE2_SECTORS: usize = 384;
+
+/// Stage-1 clears and copies stage-2 into `[0x10000, STAGE2_END)`
+const STAGE2_END: usize = 0x40000;
 
 fn flatten_bootloader(filename: &str) -> Vec<(usize, usize, Vec<u8>)> {
     let stage1 = std::fs::read(filename).unwrap();
@@ -29,26 +36,49 @@ fn flatten_bootloader(filename: &str) -> Vec<(usize, usize, Vec<u8>)> {
 }
 
 fn main() {
+    let args: Vec<String> = std::env::args().collect();
+    match args.get(1).map(|s| s.as_str()) {
+        Some("acpi-dump") => {
+            if args.len() != 4 {
+                eprintln!("Usage: {} acpi-dump <serial log> <output dir>", args[0]);
+                std::process::exit(1);
+            }
+            match acpi_dump::extract(&args[2], &args[3]) {
+                Ok(n) => println!("Extracted {} acpi tables to {}", n, args[3]),
+                Err(e) => {
+                    eprintln!("{}", e);
+                    std::process::exit(1);
+                }
+            }
+        }
+        _ => flatten_stage2(),
+    }
+}
+
+/// Flatten the stage-2 bootloader into the raw image stage-1 loads from disk
+fn flatten_stage2() {
     let sections = flatten_bootloader(
         "./bootloader/target/bootloader_config/release/bootloader");
 
     let mut bytes: Vec<u8> = Vec::new();
 
-    assert!(sections.len() != 0, "No sections found in parsed binary");
+    assert!(!sections.is_empty(), "No sections found in parsed binary");
 
     // Write the number of loadable sections to the file
     let num_sections = (sections.len() as u32).to_le_bytes();
     bytes.extend_from_slice(&num_sections);
 
     for (vaddr, memsz, raw) in sections {
+        assert!(vaddr + memsz <= STAGE2_END, "stage2 section at {:#x} too large: {:#x}", vaddr,
+                memsz);
         bytes.extend_from_slice(&(vaddr as u32).to_le_bytes());
         bytes.extend_from_slice(&(memsz as u32).to_le_bytes());
         bytes.extend_from_slice(&raw);
     }
 
-    assert!(bytes.len() < 512 * 60, "stage2 bootloader too large: {}", bytes.len());
+    assert!(bytes.len() <= 512 * STAGE2_SECTORS, "stage2 bootloader too large: {}", bytes.len());
     println!("Stage-2 Bootloader Size: {:#0x?}", bytes.len());
-    let filler = vec![0; (512 * 60) - bytes.len()];
+    let filler = vec![0; (512 * STAGE2_SECTORS) - bytes.len()];
     bytes.extend_from_slice(&filler);
 
     std::fs::write("flattened_stage2.bin", bytes)